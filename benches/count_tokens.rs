@@ -0,0 +1,54 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::collections::HashMap;
+use tiktoken_rust as tt;
+
+const CL100K_PAT_STR: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// An encoding with the cl100k_base pattern that doesn't need the network: bytes, plus the
+/// proper prefixes of the pieces of `text`, so each piece goes through the merge loop.
+fn prefix_encoding(text: &str) -> tt::Encoding {
+    let mut ranks: HashMap<Vec<u8>, usize> = (0..=255u8).map(|b| (vec![b], b as usize)).collect();
+    let regex = fancy_regex::Regex::new(CL100K_PAT_STR).unwrap();
+    for piece in regex.find_iter(text) {
+        let piece = piece.unwrap().as_str().as_bytes();
+        for end in 2..piece.len() {
+            let rank = ranks.len();
+            ranks.entry(piece[..end].to_vec()).or_insert(rank);
+        }
+    }
+    tt::EncodingBuilder::new()
+        .name("prefixes")
+        .pattern(CL100K_PAT_STR)
+        .mergeable_ranks(ranks)
+        .build()
+        .unwrap()
+}
+
+fn bench_encoding(c: &mut Criterion, name: &str, enc: &tt::Encoding, text: &str) {
+    let mut group = c.benchmark_group(name);
+    group.bench_function("encode_ordinary().len()", |b| {
+        b.iter(|| enc.encode_ordinary(black_box(text)).len())
+    });
+    group.bench_function("count_tokens()", |b| {
+        b.iter(|| enc.count_tokens(black_box(text)))
+    });
+    group.bench_function("exceeds_token_limit(4096)", |b| {
+        b.iter(|| enc.exceeds_token_limit(black_box(text), 4096))
+    });
+    group.finish();
+}
+
+fn bench_count_tokens(c: &mut Criterion) {
+    let text = "The quick brown fox jumps over the lazy dog. 我非常渴望与人工智能一起工作 👍\n"
+        .repeat(1000);
+
+    bench_encoding(c, "prefixes", &prefix_encoding(&text), &text);
+    // Needs the vocabulary file, from the network or the cache
+    match tt::get_encoding("cl100k_base") {
+        Ok(enc) => bench_encoding(c, "cl100k_base", &enc, &text),
+        Err(e) => eprintln!("Skipping cl100k_base: {}", e),
+    }
+}
+
+criterion_group!(benches, bench_count_tokens);
+criterion_main!(benches);
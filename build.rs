@@ -0,0 +1,79 @@
+//! Copies the vocabulary files of the enabled `embedded-*` features into `OUT_DIR`,
+//! after checking their SHA-256, for `include_str!` in openai_public.rs.
+//!
+//! The files are read from `TIKTOKEN_ASSETS_DIR`, or `assets/` next to Cargo.toml,
+//! see `scripts/download_assets.py`.
+
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[allow(dead_code)]
+#[path = "src/vocab_files.rs"]
+mod vocab_files;
+
+use vocab_files::*;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/vocab_files.rs");
+    println!("cargo:rerun-if-env-changed=TIKTOKEN_ASSETS_DIR");
+
+    let features: [(&str, &[VocabFile]); 5] = [
+        ("EMBEDDED_GPT2", &[GPT2_VOCAB_BPE, GPT2_ENCODER_JSON]),
+        ("EMBEDDED_R50K", &[R50K_BASE]),
+        ("EMBEDDED_P50K", &[P50K_BASE]),
+        ("EMBEDDED_CL100K", &[CL100K_BASE]),
+        ("EMBEDDED_O200K", &[O200K_BASE]),
+    ];
+    let enabled: Vec<&VocabFile> = features
+        .iter()
+        .filter(|(feature, _)| env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some())
+        .flat_map(|(_, files)| files.iter())
+        .collect();
+    if enabled.is_empty() {
+        return;
+    }
+
+    let assets_dir = match env::var_os("TIKTOKEN_ASSETS_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("assets"),
+    };
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    for file in enabled {
+        copy_checked(file, &assets_dir, &out_dir);
+    }
+}
+
+fn copy_checked(file: &VocabFile, assets_dir: &Path, out_dir: &Path) {
+    let path = assets_dir.join(file.asset);
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let contents = fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "Cannot read `{}` for the embedded-* features: {}\n\
+Run `python scripts/download_assets.py`, or set TIKTOKEN_ASSETS_DIR to a directory \
+holding the file downloaded from {}",
+            path.display(),
+            e,
+            file.url
+        )
+    });
+    let actual: String = Sha256::digest(&contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if !actual.eq_ignore_ascii_case(file.hash) {
+        panic!(
+            "Checksum mismatch for `{}`: expected {}, got {}",
+            path.display(),
+            file.hash,
+            actual
+        );
+    }
+
+    let out_path = out_dir.join(file.asset);
+    fs::create_dir_all(out_path.parent().unwrap()).unwrap();
+    fs::write(out_path, contents).unwrap();
+}
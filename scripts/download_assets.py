@@ -0,0 +1,37 @@
+"""Download the vocabulary files used by the `embedded-*` cargo features into `assets/`."""
+
+import argparse
+import urllib.request
+from pathlib import Path
+
+ASSETS = {
+    "gpt2/vocab.bpe": "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
+    "gpt2/encoder.json": "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
+    "r50k_base.tiktoken": "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
+    "p50k_base.tiktoken": "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
+    "cl100k_base.tiktoken": "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
+    "o200k_base.tiktoken": "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken",
+}
+
+
+def download(assets_dir: Path, force: bool) -> None:
+    for name, url in ASSETS.items():
+        path = assets_dir / name
+        if path.exists() and not force:
+            print(f"Skipped {path}")
+            continue
+        path.parent.mkdir(parents=True, exist_ok=True)
+        with urllib.request.urlopen(url) as response:
+            path.write_bytes(response.read())
+        print(f"Downloaded {path}")
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--force", action="store_true", help="overwrite existing files")
+    args = parser.parse_args()
+    download(Path(__file__).parent.parent / "assets", args.force)
+
+
+if __name__ == "__main__":
+    main()
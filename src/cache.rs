@@ -0,0 +1,218 @@
+use crate::core::Result;
+use crate::load::{
+    generate_cache_filename, get_cache_dir, read_file_cached_in, set_cache_dir_override, sha256_hex,
+};
+use crate::model::EncodeError;
+use crate::openai_public::{all_vocab_files, find_vocab_files};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A directory holding downloaded vocabulary files.
+///
+/// Files are named after the SHA-256 of the url they were downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VocabCache {
+    dir: PathBuf,
+}
+
+/// A vocabulary file found in the cache directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    /// The url the file was downloaded from, `None` if it is not a known vocabulary file
+    pub url: Option<&'static str>,
+    pub size: u64,
+}
+
+impl VocabCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        VocabCache { dir: dir.into() }
+    }
+
+    /// Returns the cache used by `get_encoding`, that is the one set by `set_default`,
+    /// otherwise `TIKTOKEN_CACHE_DIR`, `DATA_GYM_CACHE_DIR`, or `<tmp>/data-gym-cache`.
+    ///
+    /// An empty directory means caching is disabled.
+    pub fn current() -> Self {
+        VocabCache::new(get_cache_dir())
+    }
+
+    /// Makes `get_encoding` and friends use this cache, without touching the process env.
+    pub fn set_default(&self) {
+        set_cache_dir_override(Some(self.dir.to_string_lossy().to_string()));
+    }
+
+    /// Goes back to picking the cache directory from the env vars.
+    pub fn reset_default() {
+        set_cache_dir_override(None);
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Downloads the vocabulary files of an encoding into the cache, if they are not there yet.
+    pub fn prefetch(&self, encoding_name: &str) -> Result<()> {
+        let files = find_vocab_files(encoding_name)
+            .ok_or_else(|| EncodeError::EncodingNameError(encoding_name.to_string()))?;
+        for file in files {
+            read_file_cached_in(&self.dir.to_string_lossy(), file.url, Some(file.hash))?;
+        }
+        Ok(())
+    }
+
+    /// Lists the vocabulary files in the cache, ignoring unrelated files.
+    pub fn list(&self) -> Result<Vec<CacheEntry>> {
+        if !self.dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let known_files = all_vocab_files();
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !is_cache_filename(&file_name) {
+                continue;
+            }
+            let url = known_files
+                .iter()
+                .find(|file| generate_cache_filename(file.url) == file_name)
+                .map(|file| file.url);
+            entries.push(CacheEntry {
+                path: entry.path(),
+                url,
+                size: entry.metadata()?.len(),
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Removes the vocabulary files of an encoding from the cache.
+    /// Returns the number of removed files.
+    pub fn evict(&self, encoding_name: &str) -> Result<usize> {
+        let files = find_vocab_files(encoding_name)
+            .ok_or_else(|| EncodeError::EncodingNameError(encoding_name.to_string()))?;
+        let mut removed = 0;
+        for file in files {
+            let path = self.dir.join(generate_cache_filename(file.url));
+            if path.exists() {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes every vocabulary file from the cache, including interrupted downloads.
+    /// Returns the number of removed files.
+    pub fn clear(&self) -> Result<usize> {
+        if !self.dir.is_dir() {
+            return Ok(0);
+        }
+
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if is_cache_filename(&file_name) || is_tmp_cache_filename(&file_name) {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Checks the known vocabulary files in the cache against their expected SHA-256.
+    /// Corrupted files are removed, so they get downloaded again, and returned.
+    pub fn verify(&self) -> Result<Vec<CacheEntry>> {
+        let known_files = all_vocab_files();
+        let mut corrupted = vec![];
+        for entry in self.list()? {
+            let file = match known_files.iter().find(|file| Some(file.url) == entry.url) {
+                Some(file) => file,
+                None => continue,
+            };
+            let contents = fs::read(&entry.path)?;
+            if !sha256_hex(&contents).eq_ignore_ascii_case(file.hash) {
+                fs::remove_file(&entry.path)?;
+                corrupted.push(entry);
+            }
+        }
+        Ok(corrupted)
+    }
+}
+
+/// See `generate_cache_filename`
+fn is_cache_filename(file_name: &str) -> bool {
+    file_name.len() == 64
+        && file_name
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+}
+
+/// Downloads are written to `<cache filename>.<uuid>.tmp` before being renamed
+fn is_tmp_cache_filename(file_name: &str) -> bool {
+    file_name.len() > 64
+        && file_name.is_char_boundary(64)
+        && is_cache_filename(&file_name[..64])
+        && file_name.ends_with(".tmp")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    const CL100K_BASE_URL: &str =
+        "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken";
+
+    #[test]
+    fn test_vocab_cache() {
+        let dir = env::temp_dir().join(format!("tiktoken-cache-{}", Uuid::new_v4()));
+        let cache = VocabCache::new(&dir);
+        assert_eq!(cache.list().unwrap(), vec![]);
+        assert_eq!(cache.clear().unwrap(), 0);
+
+        fs::create_dir_all(&dir).unwrap();
+        let cl100k_path = dir.join(generate_cache_filename(CL100K_BASE_URL));
+        fs::write(&cl100k_path, "corrupted").unwrap();
+        let unknown_path = dir.join(generate_cache_filename("https://example.com/x.tiktoken"));
+        fs::write(&unknown_path, "unknown").unwrap();
+        let tmp_path = dir.join(format!("{}.{}.tmp", "A".repeat(64), Uuid::new_v4()));
+        fs::write(&tmp_path, "").unwrap();
+        fs::write(dir.join("unrelated.txt"), "").unwrap();
+
+        let entries = cache.list().unwrap();
+        assert_eq!(entries.len(), 2);
+        let cl100k = entries.iter().find(|e| e.path == cl100k_path).unwrap();
+        assert_eq!(cl100k.url, Some(CL100K_BASE_URL));
+        assert_eq!(cl100k.size, 9);
+        let unknown = entries.iter().find(|e| e.path == unknown_path).unwrap();
+        assert_eq!(unknown.url, None);
+
+        let corrupted = cache.verify().unwrap();
+        assert_eq!(corrupted.len(), 1);
+        assert_eq!(corrupted[0].path, cl100k_path);
+        assert!(!cl100k_path.exists());
+
+        fs::write(&cl100k_path, "corrupted").unwrap();
+        assert_eq!(cache.evict("r50k_base").unwrap(), 0);
+        assert_eq!(cache.evict("cl100k_base").unwrap(), 1);
+        assert!(matches!(
+            cache.evict("unknown"),
+            Err(EncodeError::EncodingNameError(_))
+        ));
+        assert!(matches!(
+            cache.prefetch("unknown"),
+            Err(EncodeError::EncodingNameError(_))
+        ));
+
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert!(dir.join("unrelated.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use crate::core::{Encoding, Result};
+use crate::model::EncodeError;
+use crate::openai_public::{IM_END, IM_SEP, IM_START};
+
+/// A message of a chat conversation, as sent to the chat completions API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    pub name: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new<R: Into<String>, C: Into<String>>(role: R, content: C) -> Self {
+        ChatMessage {
+            role: role.into(),
+            content: content.into(),
+            name: None,
+        }
+    }
+
+    pub fn with_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+/// Tokens added around the messages by the chat format of a model
+struct ChatOverheads {
+    tokens_per_message: isize,
+    tokens_per_name: isize,
+    // every reply is primed with <|start|>assistant<|message|>
+    tokens_per_reply: isize,
+}
+
+fn chat_overheads(model: &str) -> Result<ChatOverheads> {
+    // gpt-3.5-turbo-0301 puts the role and name in the same header, and drops the role when
+    // a name is given
+    if model == "gpt-3.5-turbo-0301" || model == "gpt-35-turbo-0301" {
+        return Ok(ChatOverheads {
+            tokens_per_message: 4,
+            tokens_per_name: -1,
+            tokens_per_reply: 3,
+        });
+    }
+    // Later models may change the format, so fall back to the latest known one
+    // only for the families we know of
+    if ["gpt-3.5-turbo", "gpt-35-turbo", "gpt-4"]
+        .iter()
+        .any(|family| model.starts_with(family))
+    {
+        return Ok(ChatOverheads {
+            tokens_per_message: 3,
+            tokens_per_name: 1,
+            tokens_per_reply: 3,
+        });
+    }
+    Err(EncodeError::UnsupportedChatModelError(model.to_string()))
+}
+
+/// Returns the number of prompt tokens used by a list of chat messages.
+///
+/// `encoding` should be the encoding of `model`, see `encoding_for_model`.
+/// Returns Err if the chat format of `model` is not known.
+///
+/// ```no_run
+/// use tiktoken_rust as tt;
+///
+/// let enc = tt::encoding_for_model("gpt-4").unwrap();
+/// let messages = [
+///     tt::ChatMessage::new("system", "You are a helpful assistant."),
+///     tt::ChatMessage::new("user", "Hello!").with_name("alice"),
+/// ];
+/// let n_tokens = tt::num_tokens_from_messages(&enc, "gpt-4", &messages).unwrap();
+/// ```
+pub fn num_tokens_from_messages(
+    encoding: &Encoding,
+    model: &str,
+    messages: &[ChatMessage],
+) -> Result<usize> {
+    let overheads = chat_overheads(model)?;
+
+    let mut num_tokens = overheads.tokens_per_reply;
+    for message in messages {
+        num_tokens += overheads.tokens_per_message;
+        num_tokens += encoding.encode_ordinary(&message.role).len() as isize;
+        num_tokens += encoding.encode_ordinary(&message.content).len() as isize;
+        if let Some(name) = &message.name {
+            num_tokens += overheads.tokens_per_name;
+            num_tokens += encoding.encode_ordinary(name).len() as isize;
+        }
+    }
+    Ok(num_tokens as usize)
+}
+
+/// Renders chat messages into the token ids of a ChatML prompt:
+///
+/// ```text
+/// <|im_start|>{role}<|im_sep|>{content}<|im_end|>
+/// <|im_start|>{role} name={name}<|im_sep|>{content}<|im_end|>
+/// ```
+///
+/// The markers are encoded as special tokens, while roles, names and contents are encoded
+/// as ordinary text, so a message can't inject special tokens into the prompt.
+///
+/// ```no_run
+/// use tiktoken_rust as tt;
+///
+/// let enc = tt::get_encoding("cl100k_im").unwrap();
+/// let template = tt::ChatTemplate::new(&enc).unwrap();
+/// let tokens = template.render_for_completion(&[tt::ChatMessage::new("user", "Hello!")]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChatTemplate<'a> {
+    encoding: &'a Encoding,
+    im_start: usize,
+    im_end: usize,
+    im_sep: usize,
+}
+
+impl<'a> ChatTemplate<'a> {
+    /// Returns Err if `encoding` doesn't have the ChatML special tokens, e.g. use `cl100k_im`
+    /// rather than `cl100k_base`.
+    pub fn new(encoding: &'a Encoding) -> Result<Self> {
+        let special_token = |token: &str| {
+            if encoding.special_tokens_set().contains(token) {
+                encoding.encode_single_token(token.as_bytes())
+            } else {
+                Err(EncodeError::MissingSpecialTokenError(token.to_string()))
+            }
+        };
+        Ok(ChatTemplate {
+            encoding,
+            im_start: special_token(IM_START)?,
+            im_end: special_token(IM_END)?,
+            im_sep: special_token(IM_SEP)?,
+        })
+    }
+
+    /// Renders the messages of a conversation.
+    pub fn render(&self, messages: &[ChatMessage]) -> Vec<usize> {
+        let mut tokens = vec![];
+        for message in messages {
+            self.render_header(&mut tokens, &message.role, message.name.as_deref());
+            tokens.extend(self.encoding.encode_ordinary(&message.content));
+            tokens.push(self.im_end);
+        }
+        tokens
+    }
+
+    /// Renders the messages of a conversation, followed by the header of the assistant reply
+    /// the model should complete.
+    pub fn render_for_completion(&self, messages: &[ChatMessage]) -> Vec<usize> {
+        let mut tokens = self.render(messages);
+        self.render_header(&mut tokens, "assistant", None);
+        tokens
+    }
+
+    fn render_header(&self, tokens: &mut Vec<usize>, role: &str, name: Option<&str>) {
+        tokens.push(self.im_start);
+        match name {
+            Some(name) => tokens.extend(
+                self.encoding
+                    .encode_ordinary(&format!("{} name={}", role, name)),
+            ),
+            None => tokens.extend(self.encoding.encode_ordinary(role)),
+        }
+        tokens.push(self.im_sep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::DecodeMode;
+    use crate::test_util::byte_level;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_num_tokens_from_messages() {
+        // one token per byte
+        let enc = byte_level();
+        let messages = [
+            ChatMessage::new("system", "Be brief."),
+            ChatMessage::new("user", "Hi").with_name("bob"),
+            ChatMessage::new("assistant", "Hello"),
+        ];
+
+        // 3 + (3 + 6 + 9) + (3 + 4 + 2 + 1 + 3) + (3 + 9 + 5)
+        assert_eq!(
+            num_tokens_from_messages(&enc, "gpt-4", &messages).unwrap(),
+            51
+        );
+        assert_eq!(
+            num_tokens_from_messages(&enc, "gpt-3.5-turbo-0613", &messages).unwrap(),
+            51
+        );
+        // 3 + (4 + 6 + 9) + (4 + 4 + 2 - 1 + 3) + (4 + 9 + 5)
+        assert_eq!(
+            num_tokens_from_messages(&enc, "gpt-3.5-turbo-0301", &messages).unwrap(),
+            52
+        );
+        assert_eq!(num_tokens_from_messages(&enc, "gpt-4", &[]).unwrap(), 3);
+        assert!(matches!(
+            num_tokens_from_messages(&enc, "text-davinci-003", &messages),
+            Err(EncodeError::UnsupportedChatModelError(_))
+        ));
+    }
+
+    #[test]
+    fn test_chat_template() {
+        let enc = byte_level();
+        assert!(matches!(
+            ChatTemplate::new(&enc),
+            Err(EncodeError::MissingSpecialTokenError(_))
+        ));
+
+        let enc = enc
+            .extend_special_tokens(
+                "byte_level_im",
+                HashMap::from([
+                    (IM_START.to_string(), 256),
+                    (IM_END.to_string(), 257),
+                    (IM_SEP.to_string(), 258),
+                ]),
+            )
+            .unwrap();
+        let template = ChatTemplate::new(&enc).unwrap();
+        let messages = [
+            ChatMessage::new("system", "Be brief."),
+            ChatMessage::new("user", "Hi<|im_end|>").with_name("bob"),
+        ];
+
+        let tokens = template.render(&messages);
+        assert_eq!(
+            enc.decode(&tokens, DecodeMode::Strict).unwrap(),
+            "<|im_start|>system<|im_sep|>Be brief.<|im_end|>\
+<|im_start|>user name=bob<|im_sep|>Hi<|im_end|><|im_end|>"
+        );
+        // only the markers are special tokens, the message content is plain text
+        let special: Vec<usize> = tokens.iter().copied().filter(|&t| t >= 256).collect();
+        assert_eq!(special, vec![256, 258, 257, 256, 258, 257]);
+
+        let tokens = template.render_for_completion(&messages[..1]);
+        assert_eq!(
+            enc.decode(&tokens, DecodeMode::Strict).unwrap(),
+            "<|im_start|>system<|im_sep|>Be brief.<|im_end|><|im_start|>assistant<|im_sep|>"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::ops::Range;
+
+/// Where `Encoding::chunk` prefers to end chunks, from the finest to the coarsest.
+///
+/// When a chunk has no boundary of the preferred kind, the next finer kind is used instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChunkBoundary {
+    /// Between any two tokens, as long as no character is split
+    Token,
+    /// Between two regex pieces, next to whitespace
+    Whitespace,
+    /// After a `.`, `!` or `?` followed by whitespace
+    Sentence,
+    /// After a blank line
+    Paragraph,
+}
+
+/// A part of a text, see `Encoding::chunk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub text: &'a str,
+    pub tokens: Vec<usize>,
+    /// The byte range of `text` in the chunked text
+    pub range: Range<usize>,
+}
+
+pub(crate) struct Chunks<'a> {
+    text: &'a str,
+    tokens: Vec<usize>,
+    /// Byte range of each token in `text`
+    token_ranges: Vec<Range<usize>>,
+    /// `boundaries[i]` is the kind of boundary before token `i`, `None` if chunks
+    /// can't end there
+    boundaries: Vec<Option<ChunkBoundary>>,
+    max_tokens: usize,
+    overlap: usize,
+    boundary: ChunkBoundary,
+    start: usize,
+    /// The end of the previous chunk, chunks must end after it
+    end: usize,
+}
+
+impl<'a> Chunks<'a> {
+    /// `token_ranges` are the byte ranges of `tokens` in `text`, `pieces` are the regex pieces
+    /// of `text` along with their number of tokens.
+    pub(crate) fn new(
+        text: &'a str,
+        tokens: Vec<usize>,
+        token_ranges: Vec<Range<usize>>,
+        pieces: &[(Range<usize>, usize)],
+        max_tokens: usize,
+        overlap: usize,
+        boundary: ChunkBoundary,
+    ) -> Self {
+        assert!(max_tokens > 0, "max_tokens must be positive");
+        assert!(overlap < max_tokens, "overlap must be less than max_tokens");
+
+        let mut boundaries = Vec::with_capacity(tokens.len() + 1);
+        let mut token_ranges_iter = token_ranges.iter();
+        for (_, n_tokens) in pieces {
+            for (i, range) in token_ranges_iter.by_ref().take(*n_tokens).enumerate() {
+                boundaries.push(if i == 0 {
+                    Some(piece_boundary(text, range.start))
+                } else if text.is_char_boundary(range.start) {
+                    Some(ChunkBoundary::Token)
+                } else {
+                    None
+                });
+            }
+        }
+        boundaries.push(Some(ChunkBoundary::Paragraph));
+
+        Chunks {
+            text,
+            tokens,
+            token_ranges,
+            boundaries,
+            max_tokens,
+            overlap,
+            boundary,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Returns the token the chunk starting at `self.start` ends before
+    fn chunk_end(&self) -> usize {
+        let limit = self.tokens.len().min(self.start + self.max_tokens);
+        if limit == self.tokens.len() {
+            return limit;
+        }
+
+        let min_end = self.start.max(self.end) + 1;
+        let kinds = [
+            ChunkBoundary::Paragraph,
+            ChunkBoundary::Sentence,
+            ChunkBoundary::Whitespace,
+            ChunkBoundary::Token,
+        ];
+        for kind in kinds.into_iter().filter(|&kind| kind <= self.boundary) {
+            if let Some(end) = (min_end..=limit).rfind(|&i| self.boundaries[i] >= Some(kind)) {
+                return end;
+            }
+        }
+        // A single character takes more than max_tokens tokens
+        (limit + 1..=self.tokens.len())
+            .find(|&i| self.boundaries[i].is_some())
+            .unwrap()
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Chunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start >= self.tokens.len() {
+            return None;
+        }
+
+        let end = self.chunk_end();
+        let range = self.token_ranges[self.start].start..self.token_ranges[end - 1].end;
+        let chunk = Chunk {
+            text: &self.text[range.clone()],
+            tokens: self.tokens[self.start..end].to_vec(),
+            range,
+        };
+
+        self.end = end;
+        self.start = if end == self.tokens.len() {
+            end
+        } else {
+            // Start the next chunk `overlap` tokens earlier, at most at the start of this one,
+            // without splitting a character
+            let overlap = self.overlap.min(end - self.start);
+            let mut start = end.saturating_sub(overlap).max(self.start + 1);
+            while self.boundaries[start].is_none() {
+                start += 1;
+            }
+            start
+        };
+        Some(chunk)
+    }
+}
+
+/// Returns the kind of boundary before the regex piece starting at `start`
+fn piece_boundary(text: &str, start: usize) -> ChunkBoundary {
+    let before = &text[..start];
+    let trimmed = before.trim_end();
+    let whitespace_before = &before[trimmed.len()..];
+    let whitespace_after = text[start..].starts_with(char::is_whitespace);
+
+    if whitespace_before.matches('\n').count() >= 2 {
+        ChunkBoundary::Paragraph
+    } else if (!whitespace_before.is_empty() || whitespace_after)
+        && trimmed.ends_with(['.', '!', '?'])
+    {
+        ChunkBoundary::Sentence
+    } else if !whitespace_before.is_empty() || whitespace_after {
+        ChunkBoundary::Whitespace
+    } else {
+        ChunkBoundary::Token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::byte_level;
+    use crate::{ChunkBoundary, DecodeMode, Encoding};
+
+    fn chunk_texts(
+        enc: &Encoding,
+        text: &str,
+        max_tokens: usize,
+        overlap: usize,
+        boundary: ChunkBoundary,
+    ) -> Vec<String> {
+        enc.chunk(text, max_tokens, overlap, boundary)
+            .map(|chunk| {
+                assert_eq!(chunk.text, &text[chunk.range.clone()]);
+                assert_eq!(
+                    enc.decode(&chunk.tokens, DecodeMode::Strict).unwrap(),
+                    chunk.text
+                );
+                chunk.text.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk() {
+        let enc = byte_level();
+        let text = "One two. Three four.\n\nFive six.";
+
+        assert_eq!(
+            chunk_texts(&enc, text, 12, 0, ChunkBoundary::Token),
+            vec!["One two. Thr", "ee four.\n\nFi", "ve six."]
+        );
+        assert_eq!(
+            chunk_texts(&enc, text, 16, 0, ChunkBoundary::Whitespace),
+            vec!["One two. Three ", "four.\n\nFive six."]
+        );
+        assert_eq!(
+            chunk_texts(&enc, text, 16, 0, ChunkBoundary::Sentence),
+            vec!["One two. ", "Three four.\n\n", "Five six."]
+        );
+        // falls back to sentences when no paragraph fits
+        assert_eq!(
+            chunk_texts(&enc, text, 12, 0, ChunkBoundary::Paragraph),
+            vec!["One two. ", "Three four.", "\n\nFive six."]
+        );
+        assert_eq!(
+            chunk_texts(&enc, text, 100, 0, ChunkBoundary::Paragraph),
+            vec![text]
+        );
+        assert_eq!(
+            chunk_texts(&enc, "", 10, 0, ChunkBoundary::Paragraph),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_chunk_overlap() {
+        let enc = byte_level();
+        let text = "aa bb cc dd";
+
+        assert_eq!(
+            chunk_texts(&enc, text, 6, 3, ChunkBoundary::Whitespace),
+            vec!["aa bb ", "bb cc ", "cc dd"]
+        );
+        // multibyte characters are never split, even when they don't fit in a chunk
+        assert_eq!(
+            chunk_texts(&enc, "é€b", 3, 1, ChunkBoundary::Token),
+            vec!["é", "€", "b"]
+        );
+        assert_eq!(
+            chunk_texts(&enc, "é€b", 2, 1, ChunkBoundary::Token),
+            vec!["é", "€", "b"]
+        );
+        // chunks cut at a boundary may be shorter than the overlap
+        assert_eq!(
+            chunk_texts(&enc, "ab cdefghij", 8, 4, ChunkBoundary::Whitespace),
+            vec!["ab ", "b cdefgh", "efghij"]
+        );
+        assert_eq!(
+            chunk_texts(&enc, "a\n\nbbbbbbbbbb", 10, 5, ChunkBoundary::Paragraph),
+            vec!["a\n\n", "\n\nbbbbbbbb", "bbbbbbb"]
+        );
+    }
+}
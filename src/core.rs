@@ -1,52 +1,133 @@
+use crate::chunk::{Chunk, ChunkBoundary, Chunks};
+use crate::load::load_tiktoken_bpe_file;
 use crate::model::*;
-use crate::openai_public::find_encoding_constructor;
+use crate::openai_public::{find_derived_encoding, find_encoding_constructor, list_encoding_names};
+use crate::stream::{ReaderTokens, StreamingDecoder, StreamingEncoder};
 use crate::CoreBPE;
 use fancy_regex::Regex;
+use once_cell::sync::{Lazy, OnceCell};
 use rayon::prelude::*;
 use rustc_hash::FxHashMap;
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 
 pub type Result<T> = std::result::Result<T, EncodeError>;
 
+/// Encodings created by `get_encoding`, shared by the whole process
+#[allow(clippy::type_complexity)]
+static ENCODINGS: Lazy<Mutex<HashMap<String, Arc<OnceCell<Arc<Encoding>>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Encodings are shared between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Encoding>();
+};
+
 /// Return Encoding object
-/// TODO: cache created Encoding object
-pub fn get_encoding(encoding_name: &str) -> Result<Encoding> {
+///
+/// The encoding is only created on the first call for a given name, later calls
+/// return the same shared instance.
+pub fn get_encoding(encoding_name: &str) -> Result<Arc<Encoding>> {
+    if let Some((base_name, special_tokens)) = find_derived_encoding(encoding_name) {
+        return cached_encoding(encoding_name, || {
+            get_encoding(base_name)?.extend_special_tokens(encoding_name, special_tokens)
+        });
+    }
     match find_encoding_constructor(encoding_name) {
-        Some(func) => Encoding::new(func()),
+        Some(func) => cached_encoding(encoding_name, || Encoding::new(func()?)),
         None => Err(EncodeError::EncodingNameError(encoding_name.to_string())),
     }
 }
 
+/// Concurrent calls for the same name wait for a single construction.
+/// Failures are not cached, the next call tries again.
+fn cached_encoding(
+    encoding_name: &str,
+    constructor: impl FnOnce() -> Result<Encoding>,
+) -> Result<Arc<Encoding>> {
+    let cell = ENCODINGS
+        .lock()
+        .unwrap()
+        .entry(encoding_name.to_string())
+        .or_default()
+        .clone();
+    cell.get_or_try_init(|| Ok(Arc::new(constructor()?)))
+        .cloned()
+}
+
 /// Returns the encoding used by a model.
-pub fn encoding_for_model(model_name: &str) -> Result<Encoding> {
-    let encoding_opt = MODEL_TO_ENCODING
-        .get(model_name)
-        .map(|&encoding| get_encoding(encoding));
-    if let Some(encoding) = encoding_opt {
-        return encoding;
+pub fn encoding_for_model(model_name: &str) -> Result<Arc<Encoding>> {
+    get_encoding(encoding_name_for_model(model_name)?)
+}
+
+/// Returns the name of the encoding used by a model.
+pub fn encoding_name_for_model(model_name: &str) -> Result<&'static str> {
+    if let Some(&encoding_name) = MODEL_TO_ENCODING.read().unwrap().get(model_name) {
+        return Ok(encoding_name);
     }
 
     // Check if the model matches a known prefix
     // Prefix matching avoids needing library updates for every model version release
     // Note that this can match on non-existent models (e.g., gpt-3.5-turbo-FAKE)
-    for (&model_prefix, &model_encoding_name) in MODEL_PREFIX_TO_ENCODING.iter() {
-        if model_name.starts_with(model_prefix) {
-            return get_encoding(model_encoding_name);
-        }
-    }
+    // The longest prefix wins, so registered prefixes can refine built-in ones
+    MODEL_PREFIX_TO_ENCODING
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(&model_prefix, _)| model_name.starts_with(model_prefix))
+        .max_by_key(|(&model_prefix, _)| model_prefix.len())
+        .map(|(_, &encoding_name)| encoding_name)
+        .ok_or_else(|| EncodeError::ModelNameError(model_name.to_string()))
+}
+
+/// Maps a model name to an encoding, for `encoding_for_model`.
+///
+/// Returns Err if the model is already mapped, or if the encoding doesn't exist.
+pub fn register_model(model_name: &str, encoding_name: &str) -> Result<()> {
+    register_model_in(&MODEL_TO_ENCODING, model_name, encoding_name)
+}
 
-    Err(EncodeError::ModelNameError(model_name.to_string()))
+/// Maps every model whose name starts with `model_prefix` to an encoding,
+/// for `encoding_for_model`. Exact model names take precedence over prefixes.
+///
+/// Returns Err if the prefix is already mapped, or if the encoding doesn't exist.
+pub fn register_model_prefix(model_prefix: &str, encoding_name: &str) -> Result<()> {
+    register_model_in(&MODEL_PREFIX_TO_ENCODING, model_prefix, encoding_name)
+}
+
+fn register_model_in(
+    table: &RwLock<HashMap<&'static str, &'static str>>,
+    model: &str,
+    encoding_name: &str,
+) -> Result<()> {
+    let encoding_name = list_encoding_names()
+        .into_iter()
+        .find(|&name| name == encoding_name)
+        .ok_or_else(|| EncodeError::EncodingNameError(encoding_name.to_string()))?;
+
+    let mut table = table.write().unwrap();
+    if table.contains_key(model) {
+        return Err(EncodeError::DuplicateModelNameError(model.to_string()));
+    }
+    // Registered names live as long as the process, like the built-in ones
+    let model: &'static str = Box::leak(model.to_string().into_boxed_str());
+    table.insert(model, encoding_name);
+    Ok(())
 }
 
 pub struct EncodingParam {
-    name: String,
-    pat_str: String,
-    mergeable_ranks: HashMap<Vec<u8>, usize>,
-    special_tokens: HashMap<String, usize>,
-    explicit_n_vocab: Option<usize>,
+    pub(crate) name: String,
+    pub(crate) pat_str: String,
+    pub(crate) mergeable_ranks: HashMap<Vec<u8>, usize>,
+    pub(crate) special_tokens: HashMap<String, usize>,
+    pub(crate) explicit_n_vocab: Option<usize>,
 }
 
 impl EncodingParam {
@@ -108,27 +189,7 @@ impl Encoding {
     ///                   that the number of mergeable tokens and special tokens is equal to this number.
     ///
     fn new(param: EncodingParam) -> Result<Self> {
-        let max_token_value = max(
-            param
-                .mergeable_ranks
-                .values()
-                .max()
-                .copied()
-                .unwrap_or_default(),
-            param
-                .special_tokens
-                .values()
-                .max()
-                .copied()
-                .unwrap_or_default(),
-        );
-        if let Some(n_vocab) = param.explicit_n_vocab {
-            assert_eq!(
-                param.mergeable_ranks.len() + param.special_tokens.len(),
-                n_vocab
-            );
-            assert_eq!(max_token_value, n_vocab - 1);
-        }
+        let max_token_value = validate_param(&param)?;
 
         let core_bpe = CoreBPE::new(
             convert_to_fx_hashmap(&param.mergeable_ranks),
@@ -144,6 +205,304 @@ impl Encoding {
             core_bpe,
         })
     }
+
+    /// See `StreamingEncoder`
+    pub(crate) fn encode_ordinary_stable_prefix(&self, text: &str) -> (Vec<usize>, usize) {
+        self.core_bpe._encode_ordinary_stable_prefix(text)
+    }
+
+    /// Returns the byte range of each token, given the regex pieces they were encoded from
+    /// along with their number of tokens.
+    fn token_byte_ranges(
+        &self,
+        tokens: &[usize],
+        pieces: &[(Range<usize>, usize)],
+    ) -> Vec<Range<usize>> {
+        let mut ranges = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.iter();
+        for (range, n_tokens) in pieces {
+            let mut start = range.start;
+            for token in tokens.by_ref().take(*n_tokens) {
+                let end = start + self.core_bpe.decoder[token].len();
+                ranges.push(start..end);
+                start = end;
+            }
+        }
+        ranges
+    }
+
+    /// Returns the set of allowed special tokens,
+    /// or Err if `text` contains a disallowed special token.
+    fn check_special_tokens<'a>(
+        &'a self,
+        text: &str,
+        allowed_special: AllowedSpecial<'a>,
+        disallowed_special: DisallowedSpecial,
+    ) -> Result<HashSet<&'a str>> {
+        let allowed_special_set = match allowed_special {
+            AllowedSpecial::All => self.special_tokens_set(),
+            AllowedSpecial::Allowed(allowed) => allowed,
+        };
+        let disallowed_special_set = match disallowed_special {
+            DisallowedSpecial::All => self
+                .special_tokens_set()
+                .difference(&allowed_special_set)
+                .copied()
+                .collect(),
+            DisallowedSpecial::Disallowed(disallowed) => disallowed,
+        };
+
+        if !disallowed_special_set.is_empty() {
+            let re = special_token_regex(disallowed_special_set)?;
+            if let Ok(Some(cap)) = re.captures(text) {
+                return Err(EncodeError::SpecialTokenError(String::from(
+                    cap.get(0).unwrap().as_str(),
+                )));
+            }
+        }
+
+        Ok(allowed_special_set)
+    }
+}
+
+/// Checks the ranks and special tokens of an encoding, returns the max token value.
+fn validate_param(param: &EncodingParam) -> Result<usize> {
+    let mut ranks: Vec<(usize, &Vec<u8>)> = param
+        .mergeable_ranks
+        .iter()
+        .map(|(bytes, &rank)| (rank, bytes))
+        .collect();
+    ranks.sort();
+    for window in ranks.windows(2) {
+        let ((rank, bytes), (next_rank, next_bytes)) = (window[0], window[1]);
+        if rank == next_rank {
+            return Err(EncodeError::DuplicateRankError {
+                rank,
+                first: bytes.clone(),
+                second: next_bytes.clone(),
+            });
+        }
+    }
+
+    let rank_set: HashSet<usize> = ranks.iter().map(|&(rank, _)| rank).collect();
+    validate_special_tokens(|rank| rank_set.contains(&rank), &param.special_tokens)?;
+
+    // Special tokens may fill gaps in the mergeable ranks, e.g. `<|endoftext|>` in p50k_base,
+    // so together they must be exactly 0..=max_rank. Above it, special tokens may be sparse
+    // like in cl100k_base, unless `explicit_n_vocab` asks for every value.
+    let max_rank = ranks.last().map(|&(rank, _)| rank);
+    let mut token_values: Vec<usize> = rank_set
+        .into_iter()
+        .chain(param.special_tokens.values().copied())
+        .collect();
+    token_values.sort_unstable();
+    let max_token_value = token_values.last().copied().unwrap_or_default();
+
+    if let Some(n_vocab) = param.explicit_n_vocab {
+        if token_values.len() != n_vocab {
+            return Err(EncodeError::VocabSizeMismatchError {
+                expected: n_vocab,
+                actual: token_values.len(),
+            });
+        }
+        if max_token_value + 1 != n_vocab {
+            return Err(EncodeError::VocabSizeMismatchError {
+                expected: n_vocab,
+                actual: max_token_value + 1,
+            });
+        }
+    }
+    if let Some(max_rank) = max_rank {
+        if let Some(gap) = (0..=max_rank)
+            .zip(&token_values)
+            .find(|&(i, &value)| i != value)
+        {
+            return Err(EncodeError::RankGapError(gap.0));
+        }
+    }
+
+    Ok(max_token_value)
+}
+
+/// Checks that special tokens don't reuse the value of a mergeable rank or of another
+/// special token, returns the max special token value.
+fn validate_special_tokens<F: Fn(usize) -> bool>(
+    is_mergeable_rank: F,
+    special_tokens: &HashMap<String, usize>,
+) -> Result<Option<usize>> {
+    let mut special_tokens: Vec<(&String, &usize)> = special_tokens.iter().collect();
+    special_tokens.sort_by_key(|&(token, &rank)| (rank, token));
+    for (i, &(token, &rank)) in special_tokens.iter().enumerate() {
+        let reused_by_special = i > 0 && *special_tokens[i - 1].1 == rank;
+        if is_mergeable_rank(rank) || reused_by_special {
+            return Err(EncodeError::SpecialTokenCollisionError {
+                token: token.clone(),
+                rank,
+            });
+        }
+    }
+
+    Ok(special_tokens.last().map(|&(_, &rank)| rank))
+}
+
+/// Builds an `Encoding` from its parts, reporting invalid ones as errors.
+///
+/// ```no_run
+/// use std::collections::HashMap;
+/// use tiktoken_rust as tt;
+///
+/// let enc = tt::EncodingBuilder::new()
+///     .name("bytes")
+///     .pattern(r"\S+|\s+")
+///     .mergeable_ranks((0..=255u8).map(|b| (vec![b], b as usize)).collect())
+///     .special_tokens(HashMap::from([("<|endoftext|>".to_string(), 256)]))
+///     .explicit_n_vocab(257)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EncodingBuilder {
+    name: Option<String>,
+    pat_str: Option<String>,
+    mergeable_ranks: HashMap<Vec<u8>, usize>,
+    special_tokens: HashMap<String, usize>,
+    explicit_n_vocab: Option<usize>,
+}
+
+impl EncodingBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `Encoding::new` for the meaning of each part
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn pattern<S: Into<String>>(mut self, pat_str: S) -> Self {
+        self.pat_str = Some(pat_str.into());
+        self
+    }
+
+    pub fn mergeable_ranks(mut self, mergeable_ranks: HashMap<Vec<u8>, usize>) -> Self {
+        self.mergeable_ranks = mergeable_ranks;
+        self
+    }
+
+    pub fn special_tokens(mut self, special_tokens: HashMap<String, usize>) -> Self {
+        self.special_tokens = special_tokens;
+        self
+    }
+
+    pub fn explicit_n_vocab(mut self, n_vocab: usize) -> Self {
+        self.explicit_n_vocab = Some(n_vocab);
+        self
+    }
+
+    /// Returns Err if `name` or `pattern` is missing, if the pattern is not a valid regex,
+    /// or if ranks and special tokens don't make up a consistent vocabulary.
+    pub fn build(self) -> Result<Encoding> {
+        let name = self
+            .name
+            .ok_or(EncodeError::MissingBuilderFieldError("name"))?;
+        let pat_str = self
+            .pat_str
+            .ok_or(EncodeError::MissingBuilderFieldError("pattern"))?;
+        Encoding::new(EncodingParam::new(
+            name,
+            pat_str,
+            self.mergeable_ranks,
+            self.special_tokens,
+            self.explicit_n_vocab,
+        ))
+    }
+}
+
+/// Public constructors
+impl Encoding {
+    /// Creates an Encoding object from a local `.tiktoken` file, without any network access.
+    ///
+    /// The encoding is named after the file stem, e.g. `cl100k_base` for `cl100k_base.tiktoken`.
+    /// `pat_str` and `special_tokens` must match the ones the vocabulary was trained with,
+    /// see openai_public.rs for the values used by the public encodings.
+    pub fn from_tiktoken_file<P: AsRef<Path>>(
+        path: P,
+        pat_str: &str,
+        special_tokens: HashMap<String, usize>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mergeable_ranks = load_tiktoken_bpe_file(path)?;
+
+        Encoding::new(EncodingParam::new(
+            name,
+            pat_str.to_string(),
+            mergeable_ranks,
+            special_tokens,
+            None,
+        ))
+    }
+
+    /// Creates a new Encoding with the ranks and pattern of this one, plus `extra` special tokens.
+    ///
+    /// The rank tables are shared with this encoding rather than copied or loaded again.
+    /// Returns Err if an extra token reuses the value of another token, or redefines
+    /// one of the existing special tokens with a different value.
+    ///
+    /// ```no_run
+    /// use std::collections::HashMap;
+    /// use tiktoken_rust as tt;
+    ///
+    /// let cl100k = tt::get_encoding("cl100k_base").unwrap();
+    /// let enc = cl100k
+    ///     .extend_special_tokens(
+    ///         "cl100k_im",
+    ///         HashMap::from([
+    ///             ("<|im_start|>".to_string(), 100264),
+    ///             ("<|im_end|>".to_string(), 100265),
+    ///         ]),
+    ///     )
+    ///     .unwrap();
+    /// ```
+    pub fn extend_special_tokens<S: Into<String>>(
+        &self,
+        new_name: S,
+        extra: HashMap<String, usize>,
+    ) -> Result<Encoding> {
+        let mut special_tokens = self.special_tokens.clone();
+        for (token, rank) in extra {
+            match special_tokens.get(&token) {
+                Some(&existing) if existing != rank => {
+                    return Err(EncodeError::SpecialTokenCollisionError { token, rank });
+                }
+                _ => {
+                    special_tokens.insert(token, rank);
+                }
+            }
+        }
+        let decoder = &self.core_bpe.decoder;
+        let max_token_value = max(
+            self.max_token_value,
+            validate_special_tokens(|rank| decoder.contains_key(&rank), &special_tokens)?
+                .unwrap_or_default(),
+        );
+
+        let core_bpe = self
+            .core_bpe
+            .with_special_tokens(convert_to_fx_hashmap(&special_tokens))?;
+
+        Ok(Encoding {
+            name: new_name.into(),
+            _pat_str: self._pat_str.clone(),
+            special_tokens,
+            max_token_value,
+            core_bpe,
+        })
+    }
 }
 
 /// Public interfaces for encoding
@@ -155,6 +514,38 @@ impl Encoding {
         self.core_bpe._encode_ordinary_native(text)
     }
 
+    /// Encodes the text returned by `decode` with `DecodeMode::Escape` back into tokens,
+    /// ignoring special tokens.
+    ///
+    /// The tokens decode to the exact bytes the text was decoded from, though they may differ
+    /// from the original tokens. Returns Err if the text has an invalid escape sequence.
+    /// ```no_run
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// // the second token is the start of a character
+    /// let tokens = [15339, 5486];
+    /// let text = enc.decode(&tokens, tt::DecodeMode::Escape).unwrap();
+    /// let tokens_again = enc.encode_escaped(&text).unwrap();
+    /// assert_eq!(
+    ///     enc.decode_bytes(&tokens_again).unwrap(),
+    ///     enc.decode_bytes(&tokens).unwrap()
+    /// );
+    /// ```
+    pub fn encode_escaped(&self, text: &str) -> Result<Vec<usize>> {
+        let bytes = unescape_bytes(text)?;
+        let mut tokens = vec![];
+        for (range, valid) in utf8_segments(&bytes) {
+            if valid {
+                let text = std::str::from_utf8(&bytes[range]).unwrap();
+                tokens.extend(self.encode_ordinary(text));
+            } else {
+                tokens.extend(self.core_bpe._encode_bytes_native(&bytes[range]));
+            }
+        }
+        Ok(tokens)
+    }
+
     ///Encodes a list of strings into tokens, in parallel, ignoring special tokens.
     ///
     /// This is equivalent to `encode_batch(text, disallowed_special=())` (but slightly faster).
@@ -173,36 +564,17 @@ impl Encoding {
     /// to a special token. This can be controlled on a per-token level using the `allowed_special`
     /// and `disallowed_special` parameters. In particular:
     /// - Setting `disallowed_special` to () will prevent this function from raising errors and
-    /// cause all text corresponding to special tokens to be encoded as natural text.
+    ///   cause all text corresponding to special tokens to be encoded as natural text.
     /// - Setting `allowed_special` to "All" will cause this function to treat all text
-    /// corresponding to special tokens to be encoded as special tokens.
+    ///   corresponding to special tokens to be encoded as special tokens.
     pub fn encode(
         &self,
         text: &str,
         allowed_special: AllowedSpecial,
         disallowed_special: DisallowedSpecial,
     ) -> Result<Vec<usize>> {
-        let allowed_special_set = match allowed_special {
-            AllowedSpecial::All => self.special_tokens_set(),
-            AllowedSpecial::Allowed(allowed) => allowed,
-        };
-        let disallowed_special_set = match disallowed_special {
-            DisallowedSpecial::All => self
-                .special_tokens_set()
-                .difference(&allowed_special_set)
-                .copied()
-                .collect(),
-            DisallowedSpecial::Disallowed(disallowed) => disallowed,
-        };
-
-        if !disallowed_special_set.is_empty() {
-            let re = special_token_regex(disallowed_special_set)?;
-            if let Ok(Some(cap)) = re.captures(text) {
-                return Err(EncodeError::SpecialTokenError(String::from(
-                    cap.get(0).unwrap().as_str(),
-                )));
-            }
-        }
+        let allowed_special_set =
+            self.check_special_tokens(text, allowed_special, disallowed_special)?;
 
         Ok(self.core_bpe._encode_native(text, &allowed_special_set).0)
     }
@@ -238,27 +610,8 @@ impl Encoding {
         allowed_special: AllowedSpecial,
         disallowed_special: DisallowedSpecial,
     ) -> Result<(Vec<usize>, Vec<Vec<usize>>)> {
-        let allowed_special_set = match allowed_special {
-            AllowedSpecial::All => self.special_tokens_set(),
-            AllowedSpecial::Allowed(allowed) => allowed,
-        };
-        let disallowed_special_set = match disallowed_special {
-            DisallowedSpecial::All => self
-                .special_tokens_set()
-                .difference(&allowed_special_set)
-                .copied()
-                .collect(),
-            DisallowedSpecial::Disallowed(disallowed) => disallowed,
-        };
-
-        if !disallowed_special_set.is_empty() {
-            let re = special_token_regex(disallowed_special_set)?;
-            if let Ok(Some(cap)) = re.captures(text) {
-                return Err(EncodeError::SpecialTokenError(String::from(
-                    cap.get(0).unwrap().as_str(),
-                )));
-            }
-        }
+        let allowed_special_set =
+            self.check_special_tokens(text, allowed_special, disallowed_special)?;
 
         let (tokens, completions) = self
             .core_bpe
@@ -267,6 +620,77 @@ impl Encoding {
         Ok((tokens, completions))
     }
 
+    /// Returns the number of tokens of `encode_ordinary`, without building the tokens.
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.core_bpe._count_ordinary_native(text)
+    }
+
+    /// Returns the number of tokens of `encode`, without building the tokens.
+    ///
+    /// See `encode` for more details on `allowed_special` and `disallowed_special`.
+    pub fn count_tokens_with_special(
+        &self,
+        text: &str,
+        allowed_special: AllowedSpecial,
+        disallowed_special: DisallowedSpecial,
+    ) -> Result<usize> {
+        let allowed_special_set =
+            self.check_special_tokens(text, allowed_special, disallowed_special)?;
+        Ok(self.core_bpe._count_native(text, &allowed_special_set))
+    }
+
+    /// Returns the number of tokens of `encode_ordinary`, or `limit` if there are more.
+    ///
+    /// Stops encoding once the limit is reached, so it's cheap on texts way over the limit.
+    pub fn count_tokens_up_to(&self, text: &str, limit: usize) -> usize {
+        self.core_bpe
+            ._count_ordinary_native_up_to(text, limit)
+            .min(limit)
+    }
+
+    /// Returns true if `encode_ordinary` gives more than `limit` tokens.
+    ///
+    /// Stops encoding once the limit is crossed, so it's cheap on texts way over the limit.
+    pub fn exceeds_token_limit(&self, text: &str, limit: usize) -> bool {
+        self.core_bpe
+            ._count_ordinary_native_up_to(text, limit.saturating_add(1))
+            > limit
+    }
+
+    /// Returns the number of tokens of each string for `encode_ordinary`, in parallel.
+    pub fn count_tokens_batch(&self, texts: Vec<&str>) -> Vec<usize> {
+        texts
+            .par_iter()
+            .map(|&txt| self.count_tokens(txt))
+            .collect()
+    }
+
+    /// Encodes a string into tokens like `encode_ordinary`, along with the range of each token
+    /// in the text, counted in `unit`.
+    ///
+    /// A token holding part of a multibyte character gets the whole character in its char or
+    /// UTF-16 range, so the ranges of neighbouring tokens may overlap.
+    ///
+    /// ```no_run
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// for (token, range) in enc.encode_with_offsets("hello world", tt::OffsetUnit::Utf16) {
+    ///     println!("{}: {:?}", token, range);
+    /// }
+    /// ```
+    pub fn encode_with_offsets(&self, text: &str, unit: OffsetUnit) -> Vec<(usize, Range<usize>)> {
+        let (tokens, pieces) = self.core_bpe._encode_ordinary_pieces(text);
+        let mut ranges = self.token_byte_ranges(&tokens, &pieces);
+        if unit != OffsetUnit::Byte {
+            let mut converter = OffsetConverter::new(text, unit);
+            for range in ranges.iter_mut() {
+                *range = converter.floor(range.start)..converter.ceil(range.end);
+            }
+        }
+        tokens.into_iter().zip(ranges).collect()
+    }
+
     /// Encodes text corresponding to a single token to its token value.
     ///
     /// NOTE: this will encode all special tokens.
@@ -286,12 +710,14 @@ impl Encoding {
 /// Public interfaces for decoding
 impl Encoding {
     /// Decodes a list of tokens into bytes.
-    pub fn decode_bytes(&self, tokens: &[usize]) -> Vec<u8> {
+    ///
+    /// Returns Err with the first unknown token and its position, if any.
+    pub fn decode_bytes(&self, tokens: &[usize]) -> Result<Vec<u8>> {
         self.core_bpe._decode_native(tokens)
     }
 
     /// Decodes a batch (list of lists of tokens) into a list of bytes.
-    pub fn decode_bytes_batch(self, batch: &[&[usize]]) -> Vec<Vec<u8>> {
+    pub fn decode_bytes_batch(&self, batch: &[&[usize]]) -> Vec<Result<Vec<u8>>> {
         batch
             .par_iter()
             .map(|tokens| self.decode_bytes(tokens))
@@ -304,20 +730,35 @@ impl Encoding {
     /// You can control this behaviour using the `mode` parameter.
     /// `Strict` mode does validity check and returns Err if provided bytes are not UTF-8
     /// `Replace` mode replaces invalid UTF-8 sequences with U+FFFD
+    /// See `DecodeMode` for the other modes.
     ///
+    /// Returns Err with the first unknown token and its position, if any,
+    /// see `decode_lossy` to skip them instead.
     pub fn decode(&self, tokens: &[usize], mode: DecodeMode) -> Result<String> {
-        let bytes = self.decode_bytes(tokens);
-        match mode {
-            DecodeMode::Strict => String::from_utf8(bytes).map_err(EncodeError::ConvertStringError),
-            DecodeMode::Replace => Ok(String::from_utf8_lossy(&bytes).to_string()),
-        }
+        bytes_to_string(self.decode_bytes(tokens)?, mode)
+    }
+
+    /// Decodes a list of tokens into a string, writing `placeholder` in place of unknown tokens,
+    /// e.g. ones from a model with a bigger vocabulary.
+    ///
+    /// Returns Err only if the bytes are not UTF-8 with `DecodeMode::Strict`.
+    pub fn decode_lossy(
+        &self,
+        tokens: &[usize],
+        mode: DecodeMode,
+        placeholder: &str,
+    ) -> Result<String> {
+        let bytes = self
+            .core_bpe
+            ._decode_native_lossy(tokens, placeholder.as_bytes());
+        bytes_to_string(bytes, mode)
     }
 
     /// Decodes a batch (list of lists of tokens) into a list of strings.
     pub fn decode_batch(&self, batch: &[&[usize]], mode: DecodeMode) -> Vec<Result<String>> {
         batch
             .par_iter()
-            .map(|tokens| self.decode(tokens, mode.clone()))
+            .map(|tokens| self.decode(tokens, mode))
             .collect()
     }
 
@@ -330,12 +771,15 @@ impl Encoding {
         if let Some(bytes) = self.core_bpe.special_tokens_decoder.get(&token) {
             return Ok(bytes.to_vec());
         }
-        Err(EncodeError::TokenNotFoundError(token))
+        Err(EncodeError::TokenNotFoundError {
+            token,
+            position: None,
+        })
     }
 
     /// Decodes a list of tokens into a list of bytes.
     /// Useful for visualising tokenisation.
-    pub fn decode_tokens_bytes(&self, tokens: &Vec<usize>) -> Result<Vec<Vec<u8>>> {
+    pub fn decode_tokens_bytes(&self, tokens: &[usize]) -> Result<Vec<Vec<u8>>> {
         let data: Vec<Result<Vec<u8>>> = tokens
             .par_iter()
             .map(|&token| self.decode_single_token_bytes(token))
@@ -354,29 +798,241 @@ impl Encoding {
     /// of the first character that contains bytes from the token.
     /// This will currently raise if given tokens that decode to invalid UTF-8; this behaviour may
     /// change in the future to be more permissive.
+    /// ```text
     /// >>> enc.decode_with_offsets([31373, 995])
     /// ('hello world', [0, 5])
-    pub fn decode_with_offsets(self, tokens: &Vec<usize>) -> Result<(String, Vec<usize>)> {
+    /// ```
+    ///
+    /// See `decode_with_offsets_in` for other decode modes and offset units.
+    pub fn decode_with_offsets(&self, tokens: &[usize]) -> Result<(String, Vec<usize>)> {
+        self.decode_with_offsets_in(tokens, DecodeMode::Strict, OffsetUnit::Char)
+    }
+
+    /// Decodes a list of tokens into a string and the offset of each token in it,
+    /// counted in `unit`.
+    ///
+    /// As with `decode_with_offsets`, a token starting in the middle of a character gets the
+    /// offset of that character. Invalid UTF-8 is handled according to `mode`, a token starting
+    /// in a replaced sequence gets the offset of its replacement. Special tokens are decoded
+    /// to their text, and get offsets like any other token.
+    pub fn decode_with_offsets_in(
+        &self,
+        tokens: &[usize],
+        mode: DecodeMode,
+        unit: OffsetUnit,
+    ) -> Result<(String, Vec<usize>)> {
         let token_bytes = self.decode_tokens_bytes(tokens)?;
-        let mut text_len = 0;
-        let mut offsets = vec![];
+        let bytes = token_bytes.concat();
+
+        let (text, segments) = decode_segments(&bytes, mode)?;
 
-        for token in token_bytes {
-            let offset = if token[0] >= 0x80 && token[0] < 0xC0 {
-                max(0, text_len - 1)
+        let mut converter = OffsetConverter::new(&text, unit);
+        let mut offsets = Vec::with_capacity(tokens.len());
+        let mut start = 0;
+        let mut segment = 0;
+        for token in &token_bytes {
+            while segment + 1 < segments.len() && segments[segment + 1].0 <= start {
+                segment += 1;
+            }
+            let (segment_start, text_start, copied) = segments[segment];
+            let text_offset = if copied {
+                text_start + start - segment_start
             } else {
-                max(0, text_len)
+                text_start
             };
-            offsets.push(offset);
-            text_len += token
+            offsets.push(converter.floor(text_offset));
+            start += token.len();
+        }
+
+        Ok((text, offsets))
+    }
+}
+
+/// Public interfaces for truncation
+impl Encoding {
+    /// Shortens a text to at most `max_tokens` tokens, see `TruncationStrategy`.
+    ///
+    /// Returns the shortened text along with its tokens. Texts are only cut between
+    /// characters, so fewer than `max_tokens` tokens may be kept when a token holds part
+    /// of a multibyte character. The returned tokens are the ones of the original text,
+    /// they decode to the returned text but may differ from encoding it again.
+    ///
+    /// With `Middle`, the marker counts towards `max_tokens`; when it doesn't fit,
+    /// the text is truncated as with `Head`.
+    pub fn truncate(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        strategy: TruncationStrategy,
+    ) -> (String, Vec<usize>) {
+        let tokens = self.encode_ordinary(text);
+        if tokens.len() <= max_tokens {
+            return (text.to_string(), tokens);
+        }
+
+        match strategy {
+            TruncationStrategy::Head => {
+                let (n_tokens, end) = self.truncation_head(text, &tokens, max_tokens);
+                (text[..end].to_string(), tokens[..n_tokens].to_vec())
+            }
+            TruncationStrategy::Tail => {
+                let (n_tokens, start) = self.truncation_tail(text, &tokens, max_tokens);
+                (
+                    text[start..].to_string(),
+                    tokens[tokens.len() - n_tokens..].to_vec(),
+                )
+            }
+            TruncationStrategy::Middle { marker } => {
+                let marker_tokens = self.encode_ordinary(marker);
+                if marker_tokens.len() > max_tokens {
+                    return self.truncate(text, max_tokens, TruncationStrategy::Head);
+                }
+                let budget = max_tokens - marker_tokens.len();
+                let (n_head, end) = self.truncation_head(text, &tokens, (budget + 1) / 2);
+                let (n_tail, start) = self.truncation_tail(text, &tokens, budget / 2);
+
+                let mut res_tokens = tokens[..n_head].to_vec();
+                res_tokens.extend(marker_tokens);
+                res_tokens.extend(&tokens[tokens.len() - n_tail..]);
+                ([&text[..end], marker, &text[start..]].concat(), res_tokens)
+            }
+        }
+    }
+
+    /// Returns how many of the first `max_tokens` tokens can be kept,
+    /// and where they end in the text.
+    fn truncation_head(&self, text: &str, tokens: &[usize], max_tokens: usize) -> (usize, usize) {
+        let mut n_tokens = max_tokens;
+        let mut end: usize = tokens[..n_tokens]
+            .iter()
+            .map(|token| self.core_bpe.decoder[token].len())
+            .sum();
+        while !text.is_char_boundary(end) {
+            n_tokens -= 1;
+            end -= self.core_bpe.decoder[&tokens[n_tokens]].len();
+        }
+        (n_tokens, end)
+    }
+
+    /// Returns how many of the last `max_tokens` tokens can be kept,
+    /// and where they start in the text.
+    fn truncation_tail(&self, text: &str, tokens: &[usize], max_tokens: usize) -> (usize, usize) {
+        let mut n_tokens = max_tokens;
+        let mut start = text.len()
+            - tokens[tokens.len() - n_tokens..]
                 .iter()
-                .map(|&c| if c < 0x80 || c >= 0xC0 { 1 } else { 0 })
+                .map(|token| self.core_bpe.decoder[token].len())
                 .sum::<usize>();
+        while !text.is_char_boundary(start) {
+            start += self.core_bpe.decoder[&tokens[tokens.len() - n_tokens]].len();
+            n_tokens -= 1;
         }
+        (n_tokens, start)
+    }
+}
 
-        let text = self.decode(tokens, DecodeMode::Strict)?;
+/// Public interfaces for streaming
+impl Encoding {
+    /// Returns an encoder for a text received in parts, e.g. from a network stream.
+    ///
+    /// The tokens match `encode_ordinary` on the whole text.
+    ///
+    /// ```no_run
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// let mut encoder = enc.streaming_encoder();
+    /// let mut tokens = encoder.feed(b"hello wo").unwrap();
+    /// tokens.extend(encoder.feed(b"rld").unwrap());
+    /// tokens.extend(encoder.finish().unwrap());
+    /// assert_eq!(tokens, enc.encode_ordinary("hello world"));
+    /// ```
+    pub fn streaming_encoder(&self) -> StreamingEncoder<'_> {
+        StreamingEncoder::new(self)
+    }
 
-        Ok((text, offsets))
+    /// Returns a decoder for tokens received one by one, e.g. from a streamed completion.
+    ///
+    /// Characters split between tokens are only returned once complete. Invalid UTF-8,
+    /// and incomplete characters left when flushing, are handled according to `mode`.
+    ///
+    /// ```no_run
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// let mut decoder = enc.streaming_decoder(tt::DecodeMode::Replace);
+    /// for token in enc.encode_ordinary("hello 👍") {
+    ///     if let Some(text) = decoder.push(token).unwrap() {
+    ///         print!("{}", text);
+    ///     }
+    /// }
+    /// print!("{}", decoder.flush().unwrap());
+    /// ```
+    pub fn streaming_decoder(&self, mode: DecodeMode) -> StreamingDecoder<'_> {
+        StreamingDecoder::new(self, mode)
+    }
+
+    /// Encodes a text read from `reader`, without loading it in memory.
+    ///
+    /// The tokens match `encode_ordinary` on the whole text. Iteration stops after the first
+    /// error, either an IO error or invalid UTF-8.
+    ///
+    /// ```no_run
+    /// use std::fs::File;
+    /// use std::io::BufReader;
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// let reader = BufReader::new(File::open("corpus.jsonl").unwrap());
+    /// let n_tokens = enc.encode_reader(reader).map(|token| token.unwrap()).count();
+    /// ```
+    pub fn encode_reader<'a, R: Read + 'a>(
+        &'a self,
+        reader: R,
+    ) -> impl Iterator<Item = Result<usize>> + 'a {
+        ReaderTokens::new(self, reader)
+    }
+}
+
+/// Public interfaces for chunking
+impl Encoding {
+    /// Splits a text into chunks of at most `max_tokens` tokens, each one starting `overlap`
+    /// tokens before the end of the previous one, or just after its start when it is shorter.
+    /// Each chunk ends after the previous one.
+    ///
+    /// Chunks end at the last `boundary` that fits, see `ChunkBoundary`. The text is encoded
+    /// once, the tokens of a chunk are the ones of the whole text, and may differ from
+    /// encoding the chunk on its own. A chunk only exceeds `max_tokens` when a single
+    /// character takes more tokens than that.
+    ///
+    /// Panics if `max_tokens` is 0 or `overlap` is not less than `max_tokens`.
+    ///
+    /// ```no_run
+    /// use tiktoken_rust as tt;
+    ///
+    /// let enc = tt::get_encoding("cl100k_base").unwrap();
+    /// for chunk in enc.chunk("A long document...", 512, 64, tt::ChunkBoundary::Sentence) {
+    ///     println!("{:?}: {}", chunk.range, chunk.text);
+    /// }
+    /// ```
+    pub fn chunk<'a>(
+        &self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap: usize,
+        boundary: ChunkBoundary,
+    ) -> impl Iterator<Item = Chunk<'a>> + 'a {
+        let (tokens, pieces) = self.core_bpe._encode_ordinary_pieces(text);
+        let token_ranges = self.token_byte_ranges(&tokens, &pieces);
+        Chunks::new(
+            text,
+            tokens,
+            token_ranges,
+            &pieces,
+            max_tokens,
+            overlap,
+            boundary,
+        )
     }
 }
 
@@ -411,6 +1067,170 @@ impl Encoding {
     }
 }
 
+/// Converts decoded bytes into a string, handling invalid UTF-8 according to `mode`
+pub(crate) fn bytes_to_string(bytes: Vec<u8>, mode: DecodeMode) -> Result<String> {
+    match mode {
+        DecodeMode::Strict => String::from_utf8(bytes).map_err(EncodeError::ConvertStringError),
+        DecodeMode::Replace => Ok(String::from_utf8_lossy(&bytes).to_string()),
+        _ => decode_segments(&bytes, mode).map(|(text, _)| text),
+    }
+}
+
+/// Splits bytes into runs of valid UTF-8 and invalid sequences, flagged as valid or not.
+/// Invalid sequences are the ones `String::from_utf8_lossy` replaces with a single U+FFFD.
+fn utf8_segments(bytes: &[u8]) -> Vec<(Range<usize>, bool)> {
+    let mut segments = vec![];
+    let mut start = 0;
+    while start < bytes.len() {
+        match std::str::from_utf8(&bytes[start..]) {
+            Ok(_) => {
+                segments.push((start..bytes.len(), true));
+                break;
+            }
+            Err(e) => {
+                let invalid_start = start + e.valid_up_to();
+                let invalid_end = e.error_len().map_or(bytes.len(), |len| invalid_start + len);
+                if invalid_start > start {
+                    segments.push((start..invalid_start, true));
+                }
+                segments.push((invalid_start..invalid_end, false));
+                start = invalid_end;
+            }
+        }
+    }
+    segments
+}
+
+/// A part of a decoded text: (start in bytes, start in text, whether the bytes are copied as is)
+type DecodedSegment = (usize, usize, bool);
+
+/// Decodes bytes according to `mode`, along with the segments of the text
+fn decode_segments(bytes: &[u8], mode: DecodeMode) -> Result<(String, Vec<DecodedSegment>)> {
+    let mut text = String::with_capacity(bytes.len());
+    let mut segments = vec![];
+    for (range, valid) in utf8_segments(bytes) {
+        if valid {
+            let valid_text = std::str::from_utf8(&bytes[range.clone()]).unwrap();
+            if mode != DecodeMode::Escape {
+                segments.push((range.start, text.len(), true));
+                text.push_str(valid_text);
+                continue;
+            }
+            let mut start = range.start;
+            for (i, part) in valid_text.split('\\').enumerate() {
+                if i > 0 {
+                    segments.push((start, text.len(), false));
+                    text.push_str("\\\\");
+                    start += 1;
+                }
+                segments.push((start, text.len(), true));
+                text.push_str(part);
+                start += part.len();
+            }
+            continue;
+        }
+
+        match mode {
+            DecodeMode::Strict => {
+                return Err(EncodeError::ConvertStringError(
+                    String::from_utf8(bytes[range].to_vec()).unwrap_err(),
+                ))
+            }
+            DecodeMode::Replace => {
+                segments.push((range.start, text.len(), false));
+                text.push(char::REPLACEMENT_CHARACTER);
+            }
+            DecodeMode::Ignore => segments.push((range.start, text.len(), false)),
+            DecodeMode::BackslashReplace | DecodeMode::Escape => {
+                for i in range {
+                    segments.push((i, text.len(), false));
+                    text.push_str(&format!("\\x{:02x}", bytes[i]));
+                }
+            }
+        }
+    }
+    Ok((text, segments))
+}
+
+/// Reverses the escaping of `DecodeMode::Escape`
+fn unescape_bytes(text: &str) -> Result<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1) {
+            Some(b'\\') => {
+                unescaped.push(b'\\');
+                i += 2;
+            }
+            Some(b'x') => {
+                let byte = text
+                    .get(i + 2..i + 4)
+                    .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .ok_or(EncodeError::EscapeSequenceError(i))?;
+                unescaped.push(u8::from_str_radix(byte, 16).unwrap());
+                i += 4;
+            }
+            _ => return Err(EncodeError::EscapeSequenceError(i)),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Converts increasing byte offsets of a text into `unit`
+struct OffsetConverter<'a> {
+    text: &'a str,
+    unit: OffsetUnit,
+    // the start of the character the last offset is in, in bytes and in `unit`
+    byte: usize,
+    offset: usize,
+}
+
+impl<'a> OffsetConverter<'a> {
+    fn new(text: &'a str, unit: OffsetUnit) -> Self {
+        OffsetConverter {
+            text,
+            unit,
+            byte: 0,
+            offset: 0,
+        }
+    }
+
+    fn unit_len(&self, c: char) -> usize {
+        match self.unit {
+            OffsetUnit::Byte => c.len_utf8(),
+            OffsetUnit::Char => 1,
+            OffsetUnit::Utf16 => c.len_utf16(),
+        }
+    }
+
+    /// Returns the offset of the character `byte` is in
+    fn floor(&mut self, byte: usize) -> usize {
+        while let Some(c) = self.text[self.byte..].chars().next() {
+            if self.byte + c.len_utf8() > byte {
+                break;
+            }
+            self.byte += c.len_utf8();
+            self.offset += self.unit_len(c);
+        }
+        self.offset
+    }
+
+    /// Returns the offset of the first character starting at or after `byte`
+    fn ceil(&mut self, byte: usize) -> usize {
+        let offset = self.floor(byte);
+        match self.text[self.byte..].chars().next() {
+            Some(c) if self.byte < byte => offset + self.unit_len(c),
+            _ => offset,
+        }
+    }
+}
+
 // TODO: LRU cache
 fn special_token_regex(tokens: HashSet<&str>) -> Result<Regex> {
     let inner: Vec<_> = tokens.iter().map(|&t| regex::escape(t)).collect();
@@ -429,3 +1249,595 @@ where
         .for_each(|(k, v)| _ = res.insert(k.clone(), v.clone()));
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::byte_ranks;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn byte_level_param() -> EncodingParam {
+        EncodingParam::new(
+            "byte_level".to_string(),
+            r"\S+|\s+".to_string(),
+            byte_ranks(),
+            HashMap::new(),
+            Some(256),
+        )
+    }
+
+    #[test]
+    fn test_encoding_builder() {
+        let builder = EncodingBuilder::new()
+            .name("byte_level")
+            .pattern(r"\S+|\s+")
+            .mergeable_ranks(byte_ranks())
+            .special_tokens(HashMap::from([("<|endoftext|>".to_string(), 256)]))
+            .explicit_n_vocab(257);
+        let enc = builder.clone().build().unwrap();
+        assert_eq!(enc.name(), "byte_level");
+        assert_eq!(enc.n_vocab(), 257);
+
+        assert!(matches!(
+            EncodingBuilder::new().pattern(r"\s+").build(),
+            Err(EncodeError::MissingBuilderFieldError("name"))
+        ));
+        assert!(matches!(
+            EncodingBuilder::new().name("x").build(),
+            Err(EncodeError::MissingBuilderFieldError("pattern"))
+        ));
+        assert!(matches!(
+            builder.clone().pattern("(unclosed").build(),
+            Err(EncodeError::RegexError(_))
+        ));
+        assert!(matches!(
+            builder.clone().explicit_n_vocab(300).build(),
+            Err(EncodeError::VocabSizeMismatchError {
+                expected: 300,
+                actual: 257
+            })
+        ));
+    }
+
+    #[test]
+    fn test_encoding_builder_invalid_ranks() {
+        let builder = EncodingBuilder::new().name("x").pattern(r"\S+|\s+");
+
+        let ranks = HashMap::from([(b"a".to_vec(), 0), (b"b".to_vec(), 1), (b"c".to_vec(), 1)]);
+        assert!(matches!(
+            builder.clone().mergeable_ranks(ranks).build(),
+            Err(EncodeError::DuplicateRankError { rank: 1, ref first, ref second })
+                if first == b"b" && second == b"c"
+        ));
+
+        let ranks = HashMap::from([(b"a".to_vec(), 0), (b"b".to_vec(), 2)]);
+        assert!(matches!(
+            builder.clone().mergeable_ranks(ranks).build(),
+            Err(EncodeError::RankGapError(1))
+        ));
+        let ranks = HashMap::from([(b"a".to_vec(), 1)]);
+        assert!(matches!(
+            builder.clone().mergeable_ranks(ranks).build(),
+            Err(EncodeError::RankGapError(0))
+        ));
+
+        let ranks = HashMap::from([(b"a".to_vec(), 0), (b"b".to_vec(), 1)]);
+        let special_tokens = HashMap::from([("<|x|>".to_string(), 1)]);
+        assert!(matches!(
+            builder
+                .clone()
+                .mergeable_ranks(ranks.clone())
+                .special_tokens(special_tokens)
+                .build(),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 1, .. })
+        ));
+        let special_tokens = HashMap::from([("<|x|>".to_string(), 2), ("<|y|>".to_string(), 2)]);
+        assert!(matches!(
+            builder
+                .clone()
+                .mergeable_ranks(ranks.clone())
+                .special_tokens(special_tokens)
+                .build(),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 2, ref token }) if token == "<|y|>"
+        ));
+        let special_tokens = HashMap::from([("<|x|>".to_string(), 5)]);
+        assert!(matches!(
+            builder
+                .mergeable_ranks(ranks)
+                .special_tokens(special_tokens)
+                .explicit_n_vocab(3)
+                .build(),
+            Err(EncodeError::VocabSizeMismatchError {
+                expected: 3,
+                actual: 6
+            })
+        ));
+    }
+
+    #[test]
+    fn test_encoding_builder_special_token_in_rank_gap() {
+        // like p50k_base, <|endoftext|> sits between the mergeable ranks
+        let mut ranks = byte_ranks();
+        ranks.insert(b"ab".to_vec(), 257);
+        let builder = EncodingBuilder::new()
+            .name("p50k_like")
+            .pattern(r"\S+|\s+")
+            .mergeable_ranks(ranks)
+            .special_tokens(HashMap::from([("<|endoftext|>".to_string(), 256)]));
+
+        let enc = builder.clone().explicit_n_vocab(258).build().unwrap();
+        assert_eq!(enc.n_vocab(), 258);
+        assert_eq!(
+            enc.encode(
+                "ab<|endoftext|>",
+                AllowedSpecial::All,
+                DisallowedSpecial::All
+            )
+            .unwrap(),
+            vec![257, 256]
+        );
+
+        let extended = enc
+            .extend_special_tokens(
+                "p50k_like_im",
+                HashMap::from([("<|im_start|>".to_string(), 258)]),
+            )
+            .unwrap();
+        assert_eq!(extended.n_vocab(), 259);
+        assert!(matches!(
+            enc.extend_special_tokens("x", HashMap::from([("<|x|>".to_string(), 257)])),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 257, .. })
+        ));
+
+        assert!(matches!(
+            builder
+                .special_tokens(HashMap::from([("<|endoftext|>".to_string(), 258)]))
+                .build(),
+            Err(EncodeError::RankGapError(256))
+        ));
+    }
+
+    #[test]
+    fn test_encoding_builder_sparse_special_tokens() {
+        // like cl100k_base, special tokens are sparse above the mergeable ranks
+        let special_tokens = HashMap::from([
+            ("<|endoftext|>".to_string(), 257),
+            ("<|endofprompt|>".to_string(), 276),
+        ]);
+        let enc = EncodingBuilder::new()
+            .name("cl100k_like")
+            .pattern(r"\S+|\s+")
+            .mergeable_ranks(byte_ranks())
+            .special_tokens(special_tokens.clone())
+            .build()
+            .unwrap();
+        assert_eq!(enc.n_vocab(), 277);
+        assert_eq!(
+            enc.encode(
+                "a<|endofprompt|><|endoftext|>",
+                AllowedSpecial::All,
+                DisallowedSpecial::All
+            )
+            .unwrap(),
+            vec![97, 276, 257]
+        );
+
+        assert!(matches!(
+            EncodingBuilder::new()
+                .name("cl100k_like")
+                .pattern(r"\S+|\s+")
+                .mergeable_ranks(byte_ranks())
+                .special_tokens(special_tokens)
+                .explicit_n_vocab(277)
+                .build(),
+            Err(EncodeError::VocabSizeMismatchError {
+                expected: 277,
+                actual: 258
+            })
+        ));
+    }
+
+    #[test]
+    fn test_extend_special_tokens() {
+        let enc = Encoding::new(byte_level_param()).unwrap();
+        let extended = enc
+            .extend_special_tokens(
+                "byte_level_im",
+                HashMap::from([
+                    ("<|im_start|>".to_string(), 256),
+                    ("<|im_end|>".to_string(), 258),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(extended.name(), "byte_level_im");
+        assert_eq!(extended.n_vocab(), 259);
+        assert!(Arc::ptr_eq(
+            &enc.core_bpe.encoder,
+            &extended.core_bpe.encoder
+        ));
+        assert_eq!(
+            extended
+                .encode(
+                    "<|im_start|>hi<|im_end|>",
+                    AllowedSpecial::All,
+                    DisallowedSpecial::All
+                )
+                .unwrap(),
+            vec![256, 104, 105, 258]
+        );
+        assert_eq!(
+            extended
+                .decode(&[256, 104, 105, 258], DecodeMode::Strict)
+                .unwrap(),
+            "<|im_start|>hi<|im_end|>"
+        );
+        // the original encoding is left untouched
+        assert!(enc.special_tokens_set().is_empty());
+
+        assert!(matches!(
+            enc.extend_special_tokens("x", HashMap::from([("<|x|>".to_string(), 65)])),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 65, .. })
+        ));
+        assert!(matches!(
+            extended.extend_special_tokens("x", HashMap::from([("<|x|>".to_string(), 258)])),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 258, .. })
+        ));
+        assert!(matches!(
+            extended.extend_special_tokens("x", HashMap::from([("<|im_end|>".to_string(), 300)])),
+            Err(EncodeError::SpecialTokenCollisionError { rank: 300, .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let enc = Encoding::new(byte_level_param()).unwrap();
+        let text = "hello world";
+        assert_eq!(
+            enc.truncate(text, 20, TruncationStrategy::Head),
+            (text.to_string(), enc.encode_ordinary(text))
+        );
+        assert_eq!(
+            enc.truncate(text, 5, TruncationStrategy::Head),
+            ("hello".to_string(), enc.encode_ordinary("hello"))
+        );
+        assert_eq!(
+            enc.truncate(text, 5, TruncationStrategy::Tail),
+            ("world".to_string(), enc.encode_ordinary("world"))
+        );
+        assert_eq!(
+            enc.truncate(text, 7, TruncationStrategy::Middle { marker: ".." }),
+            ("hel..ld".to_string(), enc.encode_ordinary("hel..ld"))
+        );
+        assert_eq!(
+            enc.truncate(text, 1, TruncationStrategy::Middle { marker: ".." }),
+            ("h".to_string(), enc.encode_ordinary("h"))
+        );
+        assert_eq!(
+            enc.truncate(text, 0, TruncationStrategy::Tail),
+            (String::new(), vec![])
+        );
+
+        // one token per byte, multibyte characters are never split
+        let text = "aé€b";
+        assert_eq!(
+            enc.truncate(text, 4, TruncationStrategy::Head),
+            ("aé".to_string(), enc.encode_ordinary("aé"))
+        );
+        assert_eq!(
+            enc.truncate(text, 3, TruncationStrategy::Tail),
+            ("b".to_string(), enc.encode_ordinary("b"))
+        );
+        assert_eq!(
+            enc.truncate(text, 6, TruncationStrategy::Middle { marker: "~" }),
+            ("aé~b".to_string(), enc.encode_ordinary("aé~b"))
+        );
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let mut param = byte_level_param();
+        param.mergeable_ranks.insert(b"he".to_vec(), 256);
+        param.mergeable_ranks.insert(b"hel".to_vec(), 257);
+        param
+            .special_tokens
+            .insert("<|endoftext|>".to_string(), 258);
+        param.explicit_n_vocab = Some(259);
+        let enc = Encoding::new(param).unwrap();
+
+        for text in ["", "h", "hello help", "hello <|endoftext|> hhe", "é€ 👍"] {
+            assert_eq!(enc.count_tokens(text), enc.encode_ordinary(text).len());
+            assert_eq!(
+                enc.count_tokens_with_special(text, AllowedSpecial::All, DisallowedSpecial::All)
+                    .unwrap(),
+                enc.encode(text, AllowedSpecial::All, DisallowedSpecial::All)
+                    .unwrap()
+                    .len()
+            );
+        }
+        assert_eq!(enc.count_tokens("hello <|endoftext|>"), 17);
+        assert_eq!(
+            enc.count_tokens_with_special(
+                "hello <|endoftext|>",
+                AllowedSpecial::All,
+                DisallowedSpecial::All
+            )
+            .unwrap(),
+            5
+        );
+        assert!(matches!(
+            enc.count_tokens_with_special(
+                "hello <|endoftext|>",
+                AllowedSpecial::Allowed(HashSet::new()),
+                DisallowedSpecial::All
+            ),
+            Err(EncodeError::SpecialTokenError(_))
+        ));
+        assert_eq!(
+            enc.count_tokens_batch(vec!["hello", "", "help"]),
+            vec![3, 0, 2]
+        );
+    }
+
+    #[test]
+    fn test_count_tokens_up_to() {
+        let enc = Encoding::new(byte_level_param()).unwrap();
+        let text = "hello world";
+
+        for limit in 0..15 {
+            assert_eq!(enc.count_tokens_up_to(text, limit), limit.min(11));
+            assert_eq!(enc.exceeds_token_limit(text, limit), limit < 11);
+        }
+        assert_eq!(enc.count_tokens_up_to("", 0), 0);
+        assert!(!enc.exceeds_token_limit("", 0));
+        assert!(!enc.exceeds_token_limit(text, usize::MAX));
+
+        // stops after the first piece over the limit
+        assert_eq!(enc.core_bpe._count_ordinary_native_up_to(text, 1), 5);
+        assert_eq!(enc.core_bpe._count_ordinary_native_up_to(text, 6), 6);
+    }
+
+    #[test]
+    fn test_encode_with_offsets() {
+        let enc = Encoding::new(byte_level_param()).unwrap();
+        let text = "aé €";
+        let tokens = enc.encode_ordinary(text);
+
+        let with_offsets = enc.encode_with_offsets(text, OffsetUnit::Byte);
+        assert_eq!(
+            with_offsets
+                .iter()
+                .map(|(_, range)| range.clone())
+                .collect::<Vec<_>>(),
+            vec![0..1, 1..2, 2..3, 3..4, 4..5, 5..6, 6..7]
+        );
+        assert_eq!(
+            with_offsets
+                .into_iter()
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>(),
+            tokens
+        );
+        assert_eq!(
+            enc.encode_with_offsets(text, OffsetUnit::Char),
+            vec![
+                (tokens[0], 0..1),
+                (tokens[1], 1..2),
+                (tokens[2], 1..2),
+                (tokens[3], 2..3),
+                (tokens[4], 3..4),
+                (tokens[5], 3..4),
+                (tokens[6], 3..4),
+            ]
+        );
+
+        let text = "👍x";
+        assert_eq!(
+            enc.encode_with_offsets(text, OffsetUnit::Utf16)
+                .into_iter()
+                .map(|(_, range)| range)
+                .collect::<Vec<_>>(),
+            vec![0..2, 0..2, 0..2, 0..2, 2..3]
+        );
+        assert_eq!(enc.encode_with_offsets("", OffsetUnit::Char), vec![]);
+    }
+
+    #[test]
+    fn test_decode_with_offsets() {
+        let mut param = byte_level_param();
+        param
+            .special_tokens
+            .insert("<|endoftext|>".to_string(), 256);
+        param.explicit_n_vocab = Some(257);
+        let enc = Encoding::new(param).unwrap();
+
+        let mut tokens = enc.encode_ordinary("aé👍");
+        tokens.push(256);
+        tokens.push(b'b' as usize);
+        let text = "aé👍<|endoftext|>b";
+        assert_eq!(
+            enc.decode_with_offsets(&tokens).unwrap(),
+            (text.to_string(), vec![0, 1, 1, 2, 2, 2, 2, 3, 16])
+        );
+        assert_eq!(
+            enc.decode_with_offsets_in(&tokens, DecodeMode::Strict, OffsetUnit::Byte)
+                .unwrap(),
+            (text.to_string(), vec![0, 1, 1, 3, 3, 3, 3, 7, 20])
+        );
+        assert_eq!(
+            enc.decode_with_offsets_in(&tokens, DecodeMode::Strict, OffsetUnit::Utf16)
+                .unwrap(),
+            (text.to_string(), vec![0, 1, 1, 2, 2, 2, 2, 4, 17])
+        );
+
+        // a continuation byte first, then an incomplete character before a special token
+        let tokens = vec![0x80, b'a' as usize, 0xf0, 0x9f, 256];
+        assert!(matches!(
+            enc.decode_with_offsets(&tokens),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+        assert_eq!(
+            enc.decode_with_offsets_in(&tokens, DecodeMode::Replace, OffsetUnit::Char)
+                .unwrap(),
+            (
+                "\u{fffd}a\u{fffd}<|endoftext|>".to_string(),
+                vec![0, 1, 2, 2, 3]
+            )
+        );
+        assert_eq!(
+            enc.decode_with_offsets_in(&tokens, DecodeMode::Replace, OffsetUnit::Byte)
+                .unwrap(),
+            (
+                "\u{fffd}a\u{fffd}<|endoftext|>".to_string(),
+                vec![0, 3, 4, 4, 7]
+            )
+        );
+        assert_eq!(
+            enc.decode_with_offsets_in(&[], DecodeMode::Strict, OffsetUnit::Char)
+                .unwrap(),
+            (String::new(), vec![])
+        );
+    }
+
+    #[test]
+    fn test_decode_modes() {
+        let enc = Encoding::new(byte_level_param()).unwrap();
+        // a lone continuation byte, a backslash, and an incomplete character
+        let tokens = [
+            b'a' as usize,
+            0x80,
+            b'\\' as usize,
+            b'b' as usize,
+            0xe2,
+            0x82,
+        ];
+
+        assert!(matches!(
+            enc.decode(&tokens, DecodeMode::Strict),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+        let decoded = |mode| enc.decode(&tokens, mode).unwrap();
+        assert_eq!(decoded(DecodeMode::Replace), "a\u{fffd}\\b\u{fffd}");
+        assert_eq!(decoded(DecodeMode::Ignore), "a\\b");
+        assert_eq!(decoded(DecodeMode::BackslashReplace), r"a\x80\b\xe2\x82");
+        assert_eq!(decoded(DecodeMode::Escape), r"a\x80\\b\xe2\x82");
+
+        let escaped = decoded(DecodeMode::Escape);
+        let tokens_again = enc.encode_escaped(&escaped).unwrap();
+        assert_eq!(
+            enc.decode_bytes(&tokens_again).unwrap(),
+            enc.decode_bytes(&tokens).unwrap()
+        );
+        assert_eq!(
+            enc.encode_escaped(r"\\x41\x58").unwrap(),
+            enc.encode_ordinary(r"\x41X")
+        );
+        for invalid in [r"\", r"a\b", r"\x4", r"\xzz", "\\x\u{e9}"] {
+            assert!(
+                matches!(
+                    enc.encode_escaped(invalid),
+                    Err(EncodeError::EscapeSequenceError(_))
+                ),
+                "{:?}",
+                invalid
+            );
+        }
+
+        let (text, offsets) = enc
+            .decode_with_offsets_in(&tokens, DecodeMode::Escape, OffsetUnit::Byte)
+            .unwrap();
+        assert_eq!(text, decoded(DecodeMode::Escape));
+        assert_eq!(offsets, vec![0, 1, 5, 7, 8, 12]);
+        let (text, offsets) = enc
+            .decode_with_offsets_in(&tokens, DecodeMode::Ignore, OffsetUnit::Byte)
+            .unwrap();
+        assert_eq!(text, "a\\b");
+        assert_eq!(offsets, vec![0, 1, 1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_decode_unknown_tokens() {
+        let mut param = byte_level_param();
+        param
+            .special_tokens
+            .insert("<|endoftext|>".to_string(), 256);
+        param.explicit_n_vocab = Some(257);
+        let enc = Encoding::new(param).unwrap();
+        let tokens = [b'h' as usize, 256, 1000, b'i' as usize, 257];
+
+        let err = enc.decode(&tokens, DecodeMode::Strict).unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::TokenNotFoundError {
+                token: 1000,
+                position: Some(2)
+            }
+        ));
+        assert_eq!(err.to_string(), "token `1000` not found at position 2");
+        assert!(matches!(
+            enc.decode_bytes(&tokens),
+            Err(EncodeError::TokenNotFoundError {
+                token: 1000,
+                position: Some(2)
+            })
+        ));
+        let results = enc.decode_batch(&[&tokens[..2], &tokens[3..]], DecodeMode::Replace);
+        assert_eq!(results[0].as_ref().unwrap(), "h<|endoftext|>");
+        assert!(matches!(
+            results[1],
+            Err(EncodeError::TokenNotFoundError {
+                token: 257,
+                position: Some(1)
+            })
+        ));
+
+        assert_eq!(
+            enc.decode_lossy(&tokens, DecodeMode::Strict, "<unk>")
+                .unwrap(),
+            "h<|endoftext|><unk>i<unk>"
+        );
+        assert_eq!(
+            enc.decode_single_token_bytes(1000).unwrap_err().to_string(),
+            "token `1000` not found"
+        );
+    }
+
+    #[test]
+    fn test_cached_encoding() {
+        let calls = AtomicUsize::new(0);
+        let encodings: Vec<Arc<Encoding>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        cached_encoding("test_cached_encoding", || {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            Encoding::new(byte_level_param())
+                        })
+                        .unwrap()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for enc in &encodings {
+            assert!(Arc::ptr_eq(enc, &encodings[0]));
+        }
+        assert_eq!(encodings[0].encode_ordinary("hi"), [104, 105]);
+    }
+
+    #[test]
+    fn test_cached_encoding_error() {
+        let res = cached_encoding("test_cached_encoding_error", || {
+            Err(EncodeError::EncodingNameError("broken".to_string()))
+        });
+        assert!(res.is_err());
+
+        // failures are retried
+        let enc = cached_encoding("test_cached_encoding_error", || {
+            Encoding::new(byte_level_param())
+        });
+        assert_eq!(enc.unwrap().name(), "byte_level");
+    }
+}
@@ -3,30 +3,90 @@
 //! This crate is a tokeniser for use with OpenAI's models.
 
 mod core;
-pub use crate::core::{encoding_for_model, get_encoding, Encoding, Result};
+pub use crate::core::{
+    encoding_for_model, get_encoding, register_model, register_model_prefix, Encoding,
+    EncodingBuilder, EncodingParam, Result,
+};
 
 mod model;
-pub use model::{AllowedSpecial, DecodeMode, DisallowedSpecial, EncodeError};
-
+pub use model::{
+    AllowedSpecial, DecodeMode, DisallowedSpecial, EncodeError, OffsetUnit, TruncationStrategy,
+};
+
+mod cache;
+pub use cache::{CacheEntry, VocabCache};
+
+mod chat;
+pub use chat::{num_tokens_from_messages, ChatMessage, ChatTemplate};
+
+mod chunk;
+pub use chunk::{Chunk, ChunkBoundary};
+
+mod stream;
+pub use stream::{StreamingDecoder, StreamingEncoder};
+
+// With every vocabulary embedded, nothing is loaded from files or urls anymore
+#[cfg_attr(
+    all(
+        feature = "embedded-gpt2",
+        feature = "embedded-r50k",
+        feature = "embedded-p50k",
+        feature = "embedded-cl100k",
+        feature = "embedded-o200k"
+    ),
+    allow(dead_code)
+)]
 mod load;
 mod openai_public;
-pub use openai_public::list_encoding_names;
+pub use openai_public::{list_encoding_names, register_encoding};
+mod vocab_files;
+
+#[cfg(test)]
+mod test_util;
 
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
 use std::thread;
 
 use fancy_regex::Regex;
 use rustc_hash::FxHashMap as HashMap;
 
-fn _byte_pair_merge<T>(
+/// Pieces at least this long are merged with `_byte_pair_merge_heap`
+const HEAP_MERGE_MIN_LEN: usize = 256;
+
+/// Returns the parts `piece` is merged into, as a vector of (start, rank)
+/// ending with (piece.len(), usize::MAX).
+fn _byte_pair_merge(piece: &[u8], ranks: &HashMap<Vec<u8>, usize>) -> Vec<(usize, usize)> {
+    let mut parts = vec![];
+    _byte_pair_merge_into(piece, ranks, &mut parts);
+    parts
+}
+
+/// Same as `_byte_pair_merge`, writing into `parts` so its allocation can be reused
+fn _byte_pair_merge_into(
     piece: &[u8],
     ranks: &HashMap<Vec<u8>, usize>,
-    f: impl Fn(std::ops::Range<usize>) -> T,
-) -> Vec<T> {
+    parts: &mut Vec<(usize, usize)>,
+) {
+    if piece.len() >= HEAP_MERGE_MIN_LEN {
+        *parts = _byte_pair_merge_heap(piece, ranks);
+    } else {
+        _byte_pair_merge_scan(piece, ranks, parts);
+    }
+}
+
+fn _byte_pair_merge_scan(
+    piece: &[u8],
+    ranks: &HashMap<Vec<u8>, usize>,
+    parts: &mut Vec<(usize, usize)>,
+) {
     // This is a vector of (start, rank).
     // The rank is of the byte pair starting at position start.
     // The rank of the last item in the vector is not a valid value.
-    let mut parts: Vec<(usize, usize)> = (0..piece.len() + 1).map(|i| (i, usize::MAX)).collect();
+    parts.clear();
+    parts.extend((0..piece.len() + 1).map(|i| (i, usize::MAX)));
 
     let get_rank = {
         #[inline(always)]
@@ -44,7 +104,7 @@ fn _byte_pair_merge<T>(
     // We look up the ranks once in the beginning and iteratively update
     // them during each merge, which reduces the number of rank lookups.
     for i in 0..parts.len() - 2 {
-        match get_rank(&parts, i, 0) {
+        match get_rank(parts, i, 0) {
             Some(rank) => {
                 // usize::MAX is a sentinel value and cannot be a valid rank
                 debug_assert!(rank != usize::MAX);
@@ -57,8 +117,7 @@ fn _byte_pair_merge<T>(
     }
 
     // If you have n parts and m merges, this does O(mn) work.
-    // We could do something with a heap and do O(m log n) work.
-    // It is important to consider that n is often small (<100), and as such
+    // `_byte_pair_merge_heap` does O(m log n) work, but n is often small (<100), and as such
     // the cache-locality benefits outweigh the algorithmic complexity downsides
     // of the `parts` vector data structure above.
 
@@ -87,9 +146,9 @@ fn _byte_pair_merge<T>(
             // parts[i] and parts[i-1] before removing, which could thrash
             // the cache. Thus, we update the rank calculation by skipping over
             // parts[i + 1], by invoking `get_rank!` with `skip = 1`.
-            parts[i].1 = get_rank(&parts, i, 1).unwrap_or(usize::MAX);
+            parts[i].1 = get_rank(parts, i, 1).unwrap_or(usize::MAX);
             if i > 0 {
-                parts[i - 1].1 = get_rank(&parts, i - 1, 1).unwrap_or(usize::MAX);
+                parts[i - 1].1 = get_rank(parts, i - 1, 1).unwrap_or(usize::MAX);
             }
 
             parts.remove(i + 1);
@@ -97,18 +156,70 @@ fn _byte_pair_merge<T>(
             break;
         }
     }
-    let mut out: Vec<T> = Vec::with_capacity(parts.len() - 1);
-    for i in 0..parts.len() - 1 {
-        out.push(f(parts[i].0..parts[i + 1].0));
+}
+
+/// Same as `_byte_pair_merge_scan`, in O(m log n) for long pieces like runs of whitespace
+/// or base64 blobs.
+///
+/// The parts are a linked list, and the ranks of their pairs are kept in a min-heap.
+/// Pairs are merged in the same order, lowest rank first, then leftmost first.
+fn _byte_pair_merge_heap(piece: &[u8], ranks: &HashMap<Vec<u8>, usize>) -> Vec<(usize, usize)> {
+    let len = piece.len();
+    // next[start] and prev[start] are the starts of the parts around the part at start,
+    // `len` being the end of the last part
+    let mut next: Vec<usize> = (1..=len + 1).collect();
+    let mut prev: Vec<usize> = (0..=len).map(|i| i.wrapping_sub(1)).collect();
+    let mut merged = vec![false; len + 1];
+
+    // (rank, start, end) of the pairs of parts
+    let mut heap = BinaryHeap::new();
+    for start in 0..len.saturating_sub(1) {
+        if let Some(&rank) = ranks.get(&piece[start..start + 2]) {
+            heap.push(Reverse((rank, start, start + 2)));
+        }
     }
-    out
+
+    while let Some(Reverse((_, start, end))) = heap.pop() {
+        // Skip pairs that no longer exist, pairs only grow so their end tells them apart
+        if merged[start] || next[start] >= len || next[next[start]] != end {
+            continue;
+        }
+
+        merged[next[start]] = true;
+        next[start] = end;
+        prev[end] = start;
+
+        if end < len {
+            if let Some(&rank) = ranks.get(&piece[start..next[end]]) {
+                heap.push(Reverse((rank, start, next[end])));
+            }
+        }
+        if start > 0 {
+            let prev_start = prev[start];
+            if let Some(&rank) = ranks.get(&piece[prev_start..end]) {
+                heap.push(Reverse((rank, prev_start, end)));
+            }
+        }
+    }
+
+    let mut parts = vec![];
+    let mut start = 0;
+    while start < len {
+        parts.push((start, usize::MAX));
+        start = next[start];
+    }
+    parts.push((len, usize::MAX));
+    parts
 }
 
 fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, usize>) -> Vec<usize> {
     if piece.len() == 1 {
         return vec![ranks[piece]];
     }
-    _byte_pair_merge(piece, ranks, |p| ranks[&piece[p.start..p.end]])
+    _byte_pair_merge(piece, ranks)
+        .windows(2)
+        .map(|part| ranks[&piece[part[0].0..part[1].0]])
+        .collect()
 }
 
 #[allow(dead_code)]
@@ -116,7 +227,64 @@ fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, usize>) -> Vec<
     if piece.len() == 1 {
         return vec![piece];
     }
-    _byte_pair_merge(piece, ranks, |p| &piece[p.start..p.end])
+    _byte_pair_merge(piece, ranks)
+        .windows(2)
+        .map(|part| &piece[part[0].0..part[1].0])
+        .collect()
+}
+
+/// Returns the number of tokens of `byte_pair_encode`, without looking them up.
+/// `parts` is a buffer reused between calls.
+fn byte_pair_count(
+    piece: &[u8],
+    ranks: &HashMap<Vec<u8>, usize>,
+    parts: &mut Vec<(usize, usize)>,
+) -> usize {
+    if piece.len() == 1 {
+        return 1;
+    }
+    _byte_pair_merge_into(piece, ranks, parts);
+    parts.len() - 1
+}
+
+/// Iterator over the parts of a text between allowed special tokens: the ordinary text,
+/// then the special token that ends it, if any
+struct SpecialSplits<'a> {
+    special_regex: &'a Regex,
+    text: &'a str,
+    allowed_special: &'a HashSet<&'a str>,
+    // None once the end of the text was reached
+    start: Option<usize>,
+}
+
+impl<'a> Iterator for SpecialSplits<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.start?;
+        let mut next_special;
+        let mut start_find = start;
+        loop {
+            // Find the next allowed special token, if any
+            next_special = self
+                .special_regex
+                .find_from_pos(self.text, start_find)
+                .unwrap();
+            match next_special {
+                Some(m) => {
+                    if self.allowed_special.contains(m.as_str()) {
+                        break;
+                    }
+                    start_find = m.start() + 1;
+                }
+                None => break,
+            }
+        }
+
+        let end = next_special.map_or(self.text.len(), |m| m.start());
+        self.start = next_special.map(|m| m.end());
+        Some((&self.text[start..end], next_special.map(|m| m.as_str())))
+    }
 }
 
 // Various performance notes:
@@ -179,13 +347,14 @@ fn hash_current_thread() -> usize {
 const MAX_NUM_THREADS: usize = 128;
 
 struct CoreBPE {
-    encoder: HashMap<Vec<u8>, usize>,
+    // The rank tables are shared with the encodings derived by `with_special_tokens`
+    encoder: Arc<HashMap<Vec<u8>, usize>>,
     special_tokens_encoder: HashMap<String, usize>,
-    decoder: HashMap<usize, Vec<u8>>,
+    decoder: Arc<HashMap<usize, Vec<u8>>>,
     special_tokens_decoder: HashMap<usize, Vec<u8>>,
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    sorted_token_bytes: Arc<Vec<Vec<u8>>>,
 }
 
 impl CoreBPE {
@@ -200,15 +369,33 @@ impl CoreBPE {
         &self.special_regex_tls[hash_current_thread() % MAX_NUM_THREADS]
     }
 
-    fn _decode_native(&self, tokens: &[usize]) -> Vec<u8> {
+    fn _token_bytes(&self, token: usize) -> Option<&[u8]> {
+        self.decoder
+            .get(&token)
+            .or_else(|| self.special_tokens_decoder.get(&token))
+            .map(|bytes| bytes.as_slice())
+    }
+
+    fn _decode_native(&self, tokens: &[usize]) -> Result<Vec<u8>> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
-        for token in tokens {
+        for (position, &token) in tokens.iter().enumerate() {
             let token_bytes = self
-                .decoder
-                .get(token)
-                .unwrap_or_else(|| &self.special_tokens_decoder[token]);
+                ._token_bytes(token)
+                .ok_or(EncodeError::TokenNotFoundError {
+                    token,
+                    position: Some(position),
+                })?;
             ret.extend(token_bytes);
         }
+        Ok(ret)
+    }
+
+    /// Like `_decode_native`, with `placeholder` in place of unknown tokens
+    fn _decode_native_lossy(&self, tokens: &[usize], placeholder: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for &token in tokens {
+            ret.extend(self._token_bytes(token).unwrap_or(placeholder));
+        }
         ret
     }
 
@@ -228,33 +415,125 @@ impl CoreBPE {
         ret
     }
 
-    fn _encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<usize>, usize) {
-        let special_regex = self._get_tl_special_regex();
+    fn _encode_bytes_native(&self, bytes: &[u8]) -> Vec<usize> {
+        if bytes.is_empty() {
+            return vec![];
+        }
+        if let Some(token) = self.encoder.get(bytes) {
+            return vec![*token];
+        }
+        byte_pair_encode(bytes, &self.encoder)
+    }
+
+    /// Returns the number of tokens of `_encode_ordinary_native`, without building them
+    fn _count_ordinary_native(&self, text: &str) -> usize {
+        self._count_ordinary_native_up_to(text, usize::MAX)
+    }
+
+    /// Like `_count_ordinary_native`, but stops at the first piece that brings
+    /// the count to `max_count` or more.
+    fn _count_ordinary_native_up_to(&self, text: &str, max_count: usize) -> usize {
+        let regex = self._get_tl_regex();
+        let mut count = 0;
+        let mut parts = vec![];
+        for mat in regex.find_iter(text) {
+            if count >= max_count {
+                break;
+            }
+            let piece = mat.unwrap().as_str().as_bytes();
+            if self.encoder.contains_key(piece) {
+                count += 1;
+                continue;
+            }
+            count += byte_pair_count(piece, &self.encoder, &mut parts);
+        }
+        count
+    }
+
+    /// Returns the number of tokens of `_encode_native`, without building them
+    fn _count_native(&self, text: &str, allowed_special: &HashSet<&str>) -> usize {
+        let mut count = 0;
+        for (ordinary, special) in self._split_special(text, allowed_special) {
+            count += self._count_ordinary_native(ordinary);
+            if special.is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Splits `text` at the allowed special tokens, see `SpecialSplits`
+    fn _split_special<'a>(
+        &'a self,
+        text: &'a str,
+        allowed_special: &'a HashSet<&'a str>,
+    ) -> SpecialSplits<'a> {
+        SpecialSplits {
+            special_regex: self._get_tl_special_regex(),
+            text,
+            allowed_special,
+            start: Some(0),
+        }
+    }
+
+    /// Like `_encode_ordinary_native`, but also returns the byte range of each regex piece
+    /// along with the number of tokens it was encoded to.
+    fn _encode_ordinary_pieces(&self, text: &str) -> (Vec<usize>, Vec<(Range<usize>, usize)>) {
         let regex = self._get_tl_regex();
         let mut ret = vec![];
+        let mut pieces = vec![];
+        for mat in regex.find_iter(text) {
+            let mat = mat.unwrap();
+            let piece = mat.as_str().as_bytes();
+            let n_tokens = ret.len();
+            if let Some(token) = self.encoder.get(piece) {
+                ret.push(*token);
+            } else {
+                ret.extend(&byte_pair_encode(piece, &self.encoder));
+            }
+            pieces.push((mat.range(), ret.len() - n_tokens));
+        }
+        (ret, pieces)
+    }
 
-        let mut start = 0;
-        let mut last_piece_token_len = 0;
-        loop {
-            let mut next_special;
-            let mut start_find = start;
-            loop {
-                // Find the next allowed special token, if any
-                next_special = special_regex.find_from_pos(text, start_find).unwrap();
-                match next_special {
-                    Some(m) => {
-                        if allowed_special.contains(&text[m.start()..m.end()]) {
-                            break;
-                        }
-                        start_find = m.start() + 1;
-                    }
-                    None => break,
-                }
+    /// Encodes the pieces of `text` that can't change when more text is appended to it.
+    /// Returns their tokens, and the length of the text they cover.
+    fn _encode_ordinary_stable_prefix(&self, text: &str) -> (Vec<usize>, usize) {
+        let regex = self._get_tl_regex();
+        let pieces: Vec<Range<usize>> = regex.find_iter(text).map(|m| m.unwrap().range()).collect();
+
+        // The last piece may grow, e.g. "hel" + "lo", and patterns with optional suffixes can
+        // change the piece before it, e.g. "don", "'" + "t" becomes "don't" with o200k_base.
+        // As in `_increase_last_piece_token_len`, runs of whitespace are unstable too:
+        // "\n", " " + "\n" becomes "\n \n" with cl100k_base.
+        let is_all_space =
+            |range: &Range<usize>| text[range.clone()].chars().all(char::is_whitespace);
+        let mut n_stable = pieces.len().saturating_sub(2);
+        while n_stable > 0 && is_all_space(&pieces[n_stable - 1]) {
+            n_stable -= 1;
+        }
+
+        let mut ret = vec![];
+        for piece in &pieces[..n_stable] {
+            let piece = text[piece.clone()].as_bytes();
+            if let Some(token) = self.encoder.get(piece) {
+                ret.push(*token);
+                continue;
             }
-            let end = next_special.map_or(text.len(), |m| m.start());
+            ret.extend(&byte_pair_encode(piece, &self.encoder));
+        }
+        let end = n_stable.checked_sub(1).map_or(0, |i| pieces[i].end);
+        (ret, end)
+    }
 
+    fn _encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<usize>, usize) {
+        let regex = self._get_tl_regex();
+        let mut ret = vec![];
+
+        let mut last_piece_token_len = 0;
+        for (ordinary, special) in self._split_special(text, allowed_special) {
             // Okay, here we go, compare this logic to _encode_ordinary_native
-            for mat in regex.find_iter(&text[start..end]) {
+            for mat in regex.find_iter(ordinary) {
                 let piece = mat.unwrap().as_str().as_bytes();
                 if let Some(token) = self.encoder.get(piece) {
                     last_piece_token_len = 1;
@@ -266,16 +545,11 @@ impl CoreBPE {
                 ret.extend(&tokens);
             }
 
-            match next_special {
-                // And here we push the special token
-                Some(m) => {
-                    let piece = m.as_str();
-                    let token = self.special_tokens_encoder[piece];
-                    ret.push(token);
-                    start = m.end();
-                    last_piece_token_len = 0;
-                }
-                None => break,
+            // And here we push the special token
+            if let Some(piece) = special {
+                let token = self.special_tokens_encoder[piece];
+                ret.push(token);
+                last_piece_token_len = 0;
             }
         }
 
@@ -337,7 +611,10 @@ impl CoreBPE {
         let (mut tokens, last_piece_token_len) =
             self._increase_last_piece_token_len(tokens, last_piece_token_len);
 
-        let unstable_bytes = self._decode_native(&tokens[tokens.len() - last_piece_token_len..]);
+        // Safe as the tokens were just encoded
+        let unstable_bytes = self
+            ._decode_native(&tokens[tokens.len() - last_piece_token_len..])
+            .unwrap();
         tokens.truncate(tokens.len() - last_piece_token_len);
 
         // TODO: we should try harder to find additional stable tokens
@@ -448,6 +725,31 @@ impl CoreBPE {
     ) -> Result<Self> {
         let regex = Regex::new(pattern)?;
 
+        let decoder: HashMap<usize, Vec<u8>> =
+            encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+
+        assert_eq!(encoder.len(),
+                   decoder.len(),
+                   "Encoder and decoder must be of equal length; maybe you had duplicate token indices in your encoder?");
+
+        // Clone because I don't know how to tell Rust I'm not going to change the map
+        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
+        sorted_token_bytes.sort();
+
+        CoreBPE {
+            encoder: Arc::new(encoder),
+            special_tokens_encoder: HashMap::default(),
+            decoder: Arc::new(decoder),
+            special_tokens_decoder: HashMap::default(),
+            regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
+            special_regex_tls: vec![],
+            sorted_token_bytes: Arc::new(sorted_token_bytes),
+        }
+        .with_special_tokens(special_tokens_encoder)
+    }
+
+    /// Returns a CoreBPE with the same ranks and pattern, but different special tokens.
+    fn with_special_tokens(&self, special_tokens_encoder: HashMap<String, usize>) -> Result<Self> {
         let special_regex = {
             let _parts = special_tokens_encoder
                 .keys()
@@ -456,32 +758,21 @@ impl CoreBPE {
             Regex::new(&_parts.join("|"))?
         };
 
-        let decoder: HashMap<usize, Vec<u8>> =
-            encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
-
-        assert_eq!(encoder.len(),
-                   decoder.len(),
-                   "Encoder and decoder must be of equal length; maybe you had duplicate token indices in your encoder?");
-
         let special_tokens_decoder: HashMap<usize, Vec<u8>> = special_tokens_encoder
             .iter()
             .map(|(k, v)| (*v, k.as_bytes().to_vec()))
             .collect();
 
-        // Clone because I don't know how to tell Rust I'm not going to change the map
-        let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
-        sorted_token_bytes.sort();
-
         Ok(CoreBPE {
-            encoder,
+            encoder: self.encoder.clone(),
             special_tokens_encoder,
-            decoder,
+            decoder: self.decoder.clone(),
             special_tokens_decoder,
-            regex_tls: (0..MAX_NUM_THREADS).map(|_| regex.clone()).collect(),
+            regex_tls: self.regex_tls.clone(),
             special_regex_tls: (0..MAX_NUM_THREADS)
                 .map(|_| special_regex.clone())
                 .collect(),
-            sorted_token_bytes,
+            sorted_token_bytes: self.sorted_token_bytes.clone(),
         })
     }
 }
@@ -490,7 +781,8 @@ impl CoreBPE {
 mod tests {
     use rustc_hash::FxHashMap as HashMap;
 
-    use crate::byte_pair_split;
+    use crate::test_util::Rng;
+    use crate::{_byte_pair_merge_heap, _byte_pair_merge_scan, byte_pair_split};
 
     #[test]
     fn very_simple_test() {
@@ -501,4 +793,57 @@ mod tests {
         let res = byte_pair_split(b"abcd", &ranks);
         assert_eq!(res, vec![b"ab", b"cd"]);
     }
+
+    #[test]
+    fn test_byte_pair_merge_heap_matches_scan() {
+        let alphabet = b"ab =\n";
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20 {
+            // Like a trained vocabulary, every token is a merge of two previous ones
+            let mut tokens: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+            let mut ranks: HashMap<Vec<u8>, usize> = HashMap::default();
+            for (rank, token) in tokens.iter().enumerate() {
+                ranks.insert(token.clone(), rank);
+            }
+            for _ in 0..300 {
+                let mut pick = || {
+                    if tokens.len() == 256 || rng.next(3) == 0 {
+                        vec![alphabet[rng.next(alphabet.len())]]
+                    } else {
+                        tokens[256 + rng.next(tokens.len() - 256)].clone()
+                    }
+                };
+                let merged = [pick(), pick()].concat();
+                if !ranks.contains_key(&merged) {
+                    ranks.insert(merged.clone(), tokens.len());
+                    tokens.push(merged);
+                }
+            }
+
+            for _ in 0..50 {
+                // pieces are never empty
+                let len = 1 + match rng.next(3) {
+                    0 => rng.next(20),
+                    1 => rng.next(500),
+                    _ => rng.next(3000),
+                };
+                let piece: Vec<u8> = if rng.next(4) == 0 {
+                    // a long run of a single byte, like whitespace
+                    vec![alphabet[rng.next(alphabet.len())]; len]
+                } else {
+                    (0..len)
+                        .map(|_| alphabet[rng.next(alphabet.len())])
+                        .collect()
+                };
+                let mut parts = vec![];
+                _byte_pair_merge_scan(&piece, &ranks, &mut parts);
+                assert_eq!(
+                    _byte_pair_merge_heap(&piece, &ranks),
+                    parts,
+                    "{:?}",
+                    String::from_utf8_lossy(&piece)
+                );
+            }
+        }
+    }
 }
@@ -1,10 +1,13 @@
 use crate::core::Result;
+use crate::model::EncodeError;
 use base64ct::{Base64, Encoding};
 use bstr::ByteSlice;
+use once_cell::sync::Lazy;
 use serde_json::{Map, Value};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::RwLock;
 use std::{env, fs};
 use uuid::Uuid;
 
@@ -12,70 +15,260 @@ const TIKTOKEN_CACHE_DIR: &str = "TIKTOKEN_CACHE_DIR";
 const DATA_GYM_CACHE_DIR: &str = "DATA_GYM_CACHE_DIR";
 const DATA_GYM_TMP_DIR: &str = "data-gym-cache";
 
+/// Cache directory set programmatically, takes precedence over the env vars
+static CACHE_DIR_OVERRIDE: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
 /// `blobpath` should have format like `https://<account>.blob.core.windows.net/<container>/`
 ///
 /// TODO: support more format of blob storage path. For example,
 /// Google Cloud Storage paths (gs://<bucket>)
 /// Azure Blob Storage paths (az://<account>/<container>)
+#[cfg(feature = "remote")]
 fn read_file_remote(blob_path: &str) -> Result<String> {
     let res = reqwest::blocking::get(blob_path)?.text()?;
     Ok(res)
 }
 
-fn read_file_cached(blob_path: &str) -> Result<String> {
-    let cache_dir = get_cache_dir();
+#[cfg(not(feature = "remote"))]
+fn read_file_remote(blob_path: &str) -> Result<String> {
+    Err(EncodeError::RemoteDisabledError(blob_path.to_string()))
+}
+
+/// Reads the contents of `blob_path`, which may be a `http(s)://` url, a `file://` url
+/// or a plain filesystem path. Only remote urls go through the cache.
+///
+/// If `expected_hash` is given, the SHA-256 of the contents must match it.
+pub fn read_file(blob_path: &str, expected_hash: Option<&str>) -> Result<String> {
+    if blob_path.starts_with("http://") || blob_path.starts_with("https://") {
+        return read_file_cached(blob_path, expected_hash);
+    }
+    let path = blob_path.strip_prefix("file://").unwrap_or(blob_path);
+    let contents = read_file_local(Path::new(path))?;
+    check_hash(blob_path, &contents, expected_hash)?;
+    Ok(contents)
+}
+
+fn read_file_local(path: &Path) -> Result<String> {
+    let res = fs::read_to_string(path)?;
+    Ok(res)
+}
+
+fn read_file_cached(blob_path: &str, expected_hash: Option<&str>) -> Result<String> {
+    read_file_cached_in(&get_cache_dir(), blob_path, expected_hash)
+}
+
+/// A cached file whose hash doesn't match is deleted and downloaded again.
+pub fn read_file_cached_in(
+    cache_dir: &str,
+    blob_path: &str,
+    expected_hash: Option<&str>,
+) -> Result<String> {
     if cache_dir.is_empty() {
         // disable caching
-        return read_file_remote(blob_path);
+        let contents = read_file_remote(blob_path)?;
+        check_hash(blob_path, &contents, expected_hash)?;
+        return Ok(contents);
     }
 
     let cache_filename = generate_cache_filename(blob_path);
-    let cache_path = Path::new(&cache_dir).join(&cache_filename);
+    let cache_path = Path::new(cache_dir).join(&cache_filename);
     if cache_path.exists() {
         // found caching file
         let res = fs::read_to_string(&cache_path)?;
-        return Ok(res);
+        if check_hash(blob_path, &res, expected_hash).is_ok() {
+            return Ok(res);
+        }
+        // the cached file is corrupted or stale, remove it and download again
+        fs::remove_file(&cache_path)?;
     }
 
     let contents = read_file_remote(blob_path)?;
+    check_hash(blob_path, &contents, expected_hash)?;
 
     // save contents to local cache path
     // first create tmp file and write, then rename tmp file to destination
     let tmp_file = cache_filename + "." + Uuid::new_v4().to_string().as_str() + ".tmp";
-    let tmp_cache_path = Path::new(&cache_dir).join(tmp_file);
+    let tmp_cache_path = Path::new(cache_dir).join(tmp_file);
 
-    fs::create_dir_all(&cache_dir)?;
+    fs::create_dir_all(cache_dir)?;
     fs::write(&tmp_cache_path, &contents)?;
     fs::rename(&tmp_cache_path, &cache_path)?;
 
     Ok(contents)
 }
 
-/// panic if there's one line that either `key` part is not base64 encoded,
-/// or `value` part is not a number.
-pub fn load_tiktoken_bpe(tiktoken_bpe_file: &str) -> HashMap<Vec<u8>, usize> {
-    let contents = read_file_cached(tiktoken_bpe_file).unwrap_or_default();
-    contents
-        .lines()
-        .map(|line| line.split_once(' '))
-        .filter(|item| item.is_some())
-        .map(|item| {
-            let (b64, num) = item.unwrap();
-            let key = Base64::decode_vec(b64).unwrap();
-            let val: usize = num.parse().unwrap();
-            (key, val)
-        })
+fn check_hash(blob_path: &str, contents: &str, expected_hash: Option<&str>) -> Result<()> {
+    let expected_hash = match expected_hash {
+        Some(expected_hash) => expected_hash,
+        None => return Ok(()),
+    };
+    let actual_hash = sha256_hex(contents.as_bytes());
+    if !actual_hash.eq_ignore_ascii_case(expected_hash) {
+        return Err(EncodeError::ChecksumMismatchError {
+            path: blob_path.to_string(),
+            expected: expected_hash.to_string(),
+            actual: actual_hash,
+        });
+    }
+    Ok(())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
         .collect()
 }
 
+/// Loads a `.tiktoken` file, see `parse_tiktoken_bpe` for the format.
+#[cfg_attr(
+    all(
+        feature = "embedded-r50k",
+        feature = "embedded-p50k",
+        feature = "embedded-cl100k",
+        feature = "embedded-o200k"
+    ),
+    allow(dead_code)
+)]
+pub fn load_tiktoken_bpe(
+    tiktoken_bpe_file: &str,
+    expected_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, usize>> {
+    let contents = read_file(tiktoken_bpe_file, expected_hash)?;
+    parse_tiktoken_bpe(&contents, tiktoken_bpe_file)
+}
+
+/// Loads a `.tiktoken` file from the local filesystem, never touching the network.
+pub fn load_tiktoken_bpe_file(path: &Path) -> Result<HashMap<Vec<u8>, usize>> {
+    let contents = read_file_local(path)?;
+    parse_tiktoken_bpe(&contents, &path.to_string_lossy())
+}
+
+/// Parses the contents of a `.tiktoken` file, one `<base64 token> <rank>` pair per line.
+///
+/// Returns Err if there's one line that either `key` part is not base64 encoded,
+/// or `value` part is not a number. `file` is only used for error reporting.
+pub fn parse_tiktoken_bpe(contents: &str, file: &str) -> Result<HashMap<Vec<u8>, usize>> {
+    let invalid_line = |line: usize, reason: String| EncodeError::InvalidVocabLine {
+        file: file.to_string(),
+        line,
+        reason,
+    };
+
+    let mut bpe_ranks = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (b64, num) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid_line(i + 1, "expected `<base64 token> <rank>`".to_string()))?;
+        let key = Base64::decode_vec(b64)
+            .map_err(|e| invalid_line(i + 1, format!("invalid base64 token `{}`: {}", b64, e)))?;
+        let val: usize = num
+            .parse()
+            .map_err(|e| invalid_line(i + 1, format!("invalid rank `{}`: {}", num, e)))?;
+        bpe_ranks.insert(key, val);
+    }
+    Ok(bpe_ranks)
+}
+
 /// Handle extended ascii (https://en.wikipedia.org/wiki/Extended_ASCII)
 /// Assume ISO/IEC 8859-1 (https://en.wikipedia.org/wiki/ISO/IEC_8859-1)
 /// non-whitespace printable character range:
 /// [0x21-0x7E], [0xA1-0xAD), (0xAD-0xFF]
+#[cfg_attr(feature = "embedded-gpt2", allow(dead_code))]
 pub fn data_gym_to_mergeable_bpe_ranks(
     vocab_bpe_file: &str,
     encoder_json_file: &str,
-) -> HashMap<Vec<u8>, usize> {
+    vocab_bpe_hash: Option<&str>,
+    encoder_json_hash: Option<&str>,
+) -> Result<HashMap<Vec<u8>, usize>> {
+    let vocab_bpe_contents = read_file(vocab_bpe_file, vocab_bpe_hash)?;
+    let encoder_json_contents = read_file(encoder_json_file, encoder_json_hash)?;
+    parse_data_gym(
+        &vocab_bpe_contents,
+        vocab_bpe_file,
+        &encoder_json_contents,
+        encoder_json_file,
+    )
+}
+
+/// Same as `data_gym_to_mergeable_bpe_ranks`, but works on the contents of
+/// `vocab.bpe` and `encoder.json` instead of their paths.
+/// The file names are only used for error reporting.
+pub fn parse_data_gym(
+    vocab_bpe_contents: &str,
+    vocab_bpe_file: &str,
+    encoder_json_contents: &str,
+    encoder_json_file: &str,
+) -> Result<HashMap<Vec<u8>, usize>> {
+    let (rank_to_intbyte, data_gym_byte_to_byte) = data_gym_byte_mapping();
+
+    // add the single byte tokens
+    let mut bpe_ranks: HashMap<Vec<u8>, usize> = rank_to_intbyte
+        .into_iter()
+        .enumerate()
+        .map(|(i, b)| (vec![b], i))
+        .collect();
+
+    // vocab_bpe contains the merges along with associated ranks
+    let invalid_merge = |line: usize, reason: String| EncodeError::InvalidVocabLine {
+        file: vocab_bpe_file.to_string(),
+        line,
+        reason,
+    };
+    let mut n = bpe_ranks.len();
+    for (i, line) in vocab_bpe_contents.lines().enumerate().skip(1) {
+        if line.is_empty() {
+            continue;
+        }
+        let (first, second) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid_merge(i + 1, "expected `<first> <second>`".to_string()))?;
+        let mut key = decode_data_gym(first, &data_gym_byte_to_byte)
+            .map_err(|c| invalid_merge(i + 1, format!("unknown character {:?}", c)))?;
+        key.extend(
+            decode_data_gym(second, &data_gym_byte_to_byte)
+                .map_err(|c| invalid_merge(i + 1, format!("unknown character {:?}", c)))?,
+        );
+        bpe_ranks.insert(key, n);
+        n += 1;
+    }
+
+    // check that the encoder file matches the merges file
+    // this sanity check is important since tiktoken assumes that ranks are ordered the same
+    // as merge priority
+    let invalid_encoder = |reason: String| EncodeError::InvalidVocabFile {
+        file: encoder_json_file.to_string(),
+        reason,
+    };
+    let encoder_json: Map<String, Value> =
+        serde_json::from_str(encoder_json_contents).map_err(|e| EncodeError::InvalidVocabLine {
+            file: encoder_json_file.to_string(),
+            line: e.line(),
+            reason: e.to_string(),
+        })?;
+    let mut encoder_json_loaded: HashMap<Vec<u8>, usize> = HashMap::new();
+    for (key, val) in encoder_json.iter() {
+        let token = decode_data_gym(key, &data_gym_byte_to_byte).map_err(|c| {
+            invalid_encoder(format!("unknown character {:?} in token {:?}", c, key))
+        })?;
+        let rank = val
+            .as_u64()
+            .ok_or_else(|| invalid_encoder(format!("invalid rank {} for token {:?}", val, key)))?;
+        encoder_json_loaded.insert(token, rank as usize);
+    }
+    encoder_json_loaded.remove(b"<|endoftext|>".as_bytes());
+    encoder_json_loaded.remove(b"<|startoftext|>".as_bytes());
+
+    check_data_gym_ranks(&bpe_ranks, &encoder_json_loaded)?;
+
+    Ok(bpe_ranks)
+}
+
+/// Returns the bytes in rank order, and the mapping from data gym characters to bytes.
+fn data_gym_byte_mapping() -> (Vec<u8>, HashMap<char, u8>) {
     let mut rank_to_intbyte: Vec<u8> = vec![];
     rank_to_intbyte.extend(0x21..=0x7E);
     rank_to_intbyte.extend(0xA1..0xAD);
@@ -95,64 +288,63 @@ pub fn data_gym_to_mergeable_bpe_ranks(
     }
     assert_eq!(rank_to_intbyte.len(), 256);
 
-    // add the single byte tokens
-    let mut bpe_ranks: HashMap<Vec<u8>, usize> = rank_to_intbyte
-        .into_iter()
-        .enumerate()
-        .map(|(i, b)| (vec![b], i))
-        .collect();
-
-    // vocab_bpe contains the merges along with associated ranks
-    let vocab_bpe_contents = read_file_cached(vocab_bpe_file).unwrap_or_default();
-    let bpe_merges: Vec<(&str, &str)> = vocab_bpe_contents
-        .lines()
-        .skip(1)
-        .map(|line| line.split_once(' '))
-        .filter(|item| item.is_some())
-        .flatten()
-        .collect();
+    (rank_to_intbyte, data_gym_byte_to_byte)
+}
 
-    let mut n = bpe_ranks.len();
-    for (first, second) in bpe_merges {
-        let mut key = decode_data_gym(first, &data_gym_byte_to_byte);
-        key.extend(decode_data_gym(second, &data_gym_byte_to_byte));
-        bpe_ranks.insert(key, n);
-        n += 1
+/// Checks that the ranks derived from the merges are exactly the ones of encoder.json.
+/// Reports the mismatch with the lowest rank.
+fn check_data_gym_ranks(
+    bpe_ranks: &HashMap<Vec<u8>, usize>,
+    encoder_json_loaded: &HashMap<Vec<u8>, usize>,
+) -> Result<()> {
+    let mut merges_sorted: Vec<(&Vec<u8>, &usize)> = bpe_ranks.iter().collect();
+    merges_sorted.sort_by_key(|&(_, rank)| *rank);
+    for (token, &rank) in merges_sorted {
+        let encoder_rank = encoder_json_loaded.get(token).copied();
+        if encoder_rank != Some(rank) {
+            return Err(EncodeError::DataGymMismatchError {
+                token: token.clone(),
+                merges_rank: Some(rank),
+                encoder_rank,
+            });
+        }
     }
 
-    // check that the encoder file matches the merges file
-    // this sanity check is important since tiktoken assumes that ranks are ordered the same
-    // as merge priority
-    let content = read_file_cached(encoder_json_file).unwrap_or("{}".to_string());
-    let encoder_json: Value =
-        serde_json::from_str(&content).unwrap_or(Value::Object(Map::default()));
-    let mut encoder_json_loaded: HashMap<Vec<u8>, usize> = encoder_json
-        .as_object()
-        .unwrap()
+    // every merge is in encoder.json, so anything left is missing from the merges
+    let mut extra: Vec<(&Vec<u8>, &usize)> = encoder_json_loaded
         .iter()
-        .map(|(key, val)| {
-            (
-                decode_data_gym(key, &data_gym_byte_to_byte),
-                val.as_u64().unwrap() as usize,
-            )
-        })
+        .filter(|(token, _)| !bpe_ranks.contains_key(*token))
         .collect();
-    encoder_json_loaded.remove(b"<|endoftext|>".as_bytes());
-    encoder_json_loaded.remove(b"<|endoftext|>".as_bytes());
-
-    // TODO: assert bpe_ranks == encoder_json_loaded
+    extra.sort_by_key(|&(_, rank)| *rank);
+    if let Some((token, &rank)) = extra.first() {
+        return Err(EncodeError::DataGymMismatchError {
+            token: token.to_vec(),
+            merges_rank: None,
+            encoder_rank: Some(rank),
+        });
+    }
 
-    bpe_ranks
+    Ok(())
 }
 
-fn decode_data_gym(value: &str, dict: &HashMap<char, u8>) -> Vec<u8> {
+/// Returns the first character that has no byte mapping as Err
+fn decode_data_gym(value: &str, dict: &HashMap<char, u8>) -> std::result::Result<Vec<u8>, char> {
     value
         .chars()
-        .map(|c| dict.get(&c).copied().unwrap())
+        .map(|c| dict.get(&c).copied().ok_or(c))
         .collect()
 }
 
-fn get_cache_dir() -> String {
+/// Sets or removes the programmatic override of the cache directory
+pub fn set_cache_dir_override(cache_dir: Option<String>) {
+    *CACHE_DIR_OVERRIDE.write().unwrap() = cache_dir;
+}
+
+/// An empty cache directory means caching is disabled
+pub fn get_cache_dir() -> String {
+    if let Some(cache_dir) = CACHE_DIR_OVERRIDE.read().unwrap().as_ref() {
+        return cache_dir.clone();
+    }
     env::var(TIKTOKEN_CACHE_DIR)
         .or(env::var(DATA_GYM_CACHE_DIR))
         .unwrap_or(
@@ -163,7 +355,7 @@ fn get_cache_dir() -> String {
         )
 }
 
-fn generate_cache_filename(blob_path: &str) -> String {
+pub fn generate_cache_filename(blob_path: &str) -> String {
     let cache_key = Sha256::digest(blob_path);
     let hash_items: Vec<String> = cache_key.iter().map(|k| format!("{:02X?}", k)).collect();
 
@@ -189,6 +381,16 @@ mod tests {
         env::remove_var(DATA_GYM_CACHE_DIR);
         let res = get_cache_dir();
         assert!(res.ends_with(DATA_GYM_TMP_DIR));
+
+        env::set_var(TIKTOKEN_CACHE_DIR, "/tiktoken/cache/dir/");
+        set_cache_dir_override(Some("/override/cache/dir/".to_string()));
+        let res = get_cache_dir();
+        assert_eq!(res, "/override/cache/dir/");
+
+        set_cache_dir_override(None);
+        let res = get_cache_dir();
+        assert_eq!(res, "/tiktoken/cache/dir/");
+        env::remove_var(TIKTOKEN_CACHE_DIR);
     }
 
     #[test]
@@ -199,4 +401,180 @@ mod tests {
         );
         assert_eq!(&res, expected);
     }
+
+    #[test]
+    fn test_load_tiktoken_bpe_local() {
+        let dir = env::temp_dir().join(format!("tiktoken-load-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tiny.tiktoken");
+        fs::write(&path, "YQ== 0\nYg== 1\nYWI= 2\n").unwrap();
+
+        let expected: HashMap<Vec<u8>, usize> =
+            HashMap::from([(b"a".to_vec(), 0), (b"b".to_vec(), 1), (b"ab".to_vec(), 2)]);
+        assert_eq!(load_tiktoken_bpe_file(&path).unwrap(), expected);
+        assert_eq!(
+            load_tiktoken_bpe(path.to_str().unwrap(), None).unwrap(),
+            expected
+        );
+        assert_eq!(
+            load_tiktoken_bpe(&format!("file://{}", path.to_str().unwrap()), None).unwrap(),
+            expected
+        );
+        assert!(load_tiktoken_bpe_file(&dir.join("missing.tiktoken")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_checksum() {
+        let dir = env::temp_dir().join(format!("tiktoken-load-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tiny.tiktoken");
+        fs::write(&path, "YQ== 0\n").unwrap();
+        let hash = "a0a4ee03c3db3ba80c9a2ef3f9ebdb9ad30fbb4e02c5e6b96f5b0e5d1d5d6d11";
+        let actual = sha256_hex(b"YQ== 0\n");
+
+        assert!(read_file(path.to_str().unwrap(), Some(&actual)).is_ok());
+        assert!(read_file(path.to_str().unwrap(), Some(&actual.to_uppercase())).is_ok());
+        assert!(matches!(
+            read_file(path.to_str().unwrap(), Some(hash)),
+            Err(EncodeError::ChecksumMismatchError { ref expected, .. }) if expected == hash
+        ));
+
+        // a valid cache hit is served without touching the network
+        let cache_dir = dir.join("cache");
+        fs::create_dir_all(&cache_dir).unwrap();
+        let cache_dir = cache_dir.to_str().unwrap();
+        let url = "https://example.invalid/tiny.tiktoken";
+        let cache_path = Path::new(cache_dir).join(generate_cache_filename(url));
+        fs::write(&cache_path, "YQ== 0\n").unwrap();
+        assert_eq!(
+            read_file_cached_in(cache_dir, url, Some(&actual)).unwrap(),
+            "YQ== 0\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Without the `remote` feature, downloading again fails without touching the network
+    #[cfg(not(feature = "remote"))]
+    #[test]
+    fn test_read_file_corrupted_cache() {
+        let cache_dir = env::temp_dir().join(format!("tiktoken-load-{}", Uuid::new_v4()));
+        fs::create_dir_all(&cache_dir).unwrap();
+        let url = "https://example.invalid/tiny.tiktoken";
+        let cache_path = cache_dir.join(generate_cache_filename(url));
+        fs::write(&cache_path, "YQ== 1\n").unwrap();
+
+        // a corrupted cache file is removed, then downloaded again
+        assert!(matches!(
+            read_file_cached_in(
+                cache_dir.to_str().unwrap(),
+                url,
+                Some(&sha256_hex(b"YQ== 0\n"))
+            ),
+            Err(EncodeError::RemoteDisabledError(_))
+        ));
+        assert!(!cache_path.exists());
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tiktoken_bpe_invalid() {
+        let err = parse_tiktoken_bpe("YQ== 0\nYg==1\n", "bad.tiktoken").unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::InvalidVocabLine { ref file, line: 2, .. } if file == "bad.tiktoken"
+        ));
+        let err = parse_tiktoken_bpe("YQ== 0\n!!!! 1\n", "bad.tiktoken").unwrap_err();
+        assert!(matches!(err, EncodeError::InvalidVocabLine { line: 2, .. }));
+        let err = parse_tiktoken_bpe("YQ== zero\n", "bad.tiktoken").unwrap_err();
+        assert!(matches!(err, EncodeError::InvalidVocabLine { line: 1, .. }));
+    }
+
+    /// Builds an encoder.json matching the given merges
+    fn data_gym_encoder_json(merges: &[(&str, &str)]) -> String {
+        let (rank_to_intbyte, data_gym_byte_to_byte) = data_gym_byte_mapping();
+        let byte_to_char: HashMap<u8, char> = data_gym_byte_to_byte
+            .iter()
+            .map(|(&c, &b)| (b, c))
+            .collect();
+        let mut encoder = Map::new();
+        for (rank, b) in rank_to_intbyte.iter().enumerate() {
+            encoder.insert(byte_to_char[b].to_string(), Value::from(rank));
+        }
+        for (i, (first, second)) in merges.iter().enumerate() {
+            encoder.insert(format!("{}{}", first, second), Value::from(256 + i));
+        }
+        encoder.insert("<|endoftext|>".to_string(), Value::from(256 + merges.len()));
+        Value::Object(encoder).to_string()
+    }
+
+    #[test]
+    fn test_parse_data_gym() {
+        let vocab_bpe = "#version: 0.2\nh e\nl l\nhe ll\n";
+        let encoder_json = data_gym_encoder_json(&[("h", "e"), ("l", "l"), ("he", "ll")]);
+        let ranks = parse_data_gym(vocab_bpe, "vocab.bpe", &encoder_json, "e.json").unwrap();
+        assert_eq!(ranks.len(), 259);
+        assert_eq!(ranks[b"he".as_slice()], 256);
+        assert_eq!(ranks[b"hell".as_slice()], 258);
+
+        // merges out of order with encoder.json
+        let vocab_bpe = "#version: 0.2\nl l\nh e\nhe ll\n";
+        let err = parse_data_gym(vocab_bpe, "vocab.bpe", &encoder_json, "e.json").unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::DataGymMismatchError {
+                ref token,
+                merges_rank: Some(256),
+                encoder_rank: Some(257),
+            } if token == b"ll"
+        ));
+
+        // encoder.json has a token the merges don't produce
+        let vocab_bpe = "#version: 0.2\nh e\nl l\n";
+        let err = parse_data_gym(vocab_bpe, "vocab.bpe", &encoder_json, "e.json").unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::DataGymMismatchError {
+                ref token,
+                merges_rank: None,
+                encoder_rank: Some(258),
+            } if token == b"hell"
+        ));
+    }
+
+    #[test]
+    fn test_parse_data_gym_invalid() {
+        let err = parse_data_gym(
+            "#version: 0.2\nh e\nh \u{263a}\n",
+            "vocab.bpe",
+            "{}",
+            "e.json",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::InvalidVocabLine { ref file, line: 3, .. } if file == "vocab.bpe"
+        ));
+        let err = parse_data_gym("#version: 0.2\n", "vocab.bpe", "{", "e.json").unwrap_err();
+        assert!(matches!(
+            err,
+            EncodeError::InvalidVocabLine { ref file, .. } if file == "e.json"
+        ));
+        let err =
+            parse_data_gym("#version: 0.2\n", "vocab.bpe", r#"{"a": "0"}"#, "e.json").unwrap_err();
+        assert!(matches!(err, EncodeError::InvalidVocabFile { .. }));
+    }
+
+    #[cfg(not(feature = "remote"))]
+    #[test]
+    fn test_read_file_remote_disabled() {
+        let url = "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken";
+        assert!(matches!(
+            read_file_remote(url),
+            Err(EncodeError::RemoteDisabledError(path)) if path == url
+        ));
+    }
 }
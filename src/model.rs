@@ -3,6 +3,7 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::io;
 use std::string::FromUtf8Error;
+use std::sync::RwLock;
 use thiserror::Error;
 
 #[derive(Debug, Clone)]
@@ -17,18 +18,54 @@ pub enum DisallowedSpecial<'a> {
     Disallowed(HashSet<&'a str>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecodeMode {
     Strict,
     Replace, // replace invalid character
+    /// Drop invalid bytes
+    Ignore,
+    /// Write each invalid byte as `\xNN`
+    BackslashReplace,
+    /// Like `BackslashReplace`, also writing `\` as `\\`, so `Encoding::encode_escaped`
+    /// recovers the exact bytes
+    Escape,
+}
+
+/// The unit text offsets are counted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetUnit {
+    /// UTF-8 bytes, for indexing a `&str`
+    Byte,
+    /// Unicode scalar values, as returned by `str::chars`
+    Char,
+    /// UTF-16 code units, as used by JavaScript and many editors
+    Utf16,
+}
+
+/// Which tokens `Encoding::truncate` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncationStrategy<'a> {
+    /// Keep the first tokens
+    Head,
+    /// Keep the last tokens
+    Tail,
+    /// Keep the first and last tokens, with `marker` in between
+    Middle { marker: &'a str },
 }
 
 #[derive(Debug, Error)]
 pub enum EncodeError {
     #[error("regex error: {0}")]
     RegexError(#[from] fancy_regex::Error),
-    #[error("token `{0}` not found")]
-    TokenNotFoundError(usize),
+    #[error(
+        "token `{token}` not found{}",
+        .position.map(|p| format!(" at position {}", p)).unwrap_or_default()
+    )]
+    TokenNotFoundError {
+        token: usize,
+        /// The index of the token in the decoded list, if any
+        position: Option<usize>,
+    },
     #[error("could not encode `{0:?}` to token")]
     TokenEncodeError(Vec<u8>),
     #[error(
@@ -41,32 +78,94 @@ To disable this check for all special tokens, pass `disallowed_special=()`.\n"
     SpecialTokenError(String),
     #[error("convert bytes to string error: {0}")]
     ConvertStringError(#[from] FromUtf8Error),
+    #[error("invalid escape sequence at byte {0}")]
+    EscapeSequenceError(usize),
     #[error(
         "Could not automatically map {0} to a tokeniser.
 Please use `tiktoken_rust::get_encoding` to explicitly get the tokeniser you expect."
     )]
     ModelNameError(String),
+    #[error("Encoding has no `{0}` special token")]
+    MissingSpecialTokenError(String),
+    #[error("Counting chat tokens is not supported for model {0}")]
+    UnsupportedChatModelError(String),
     #[error("Unknown encoding {0}")]
     EncodingNameError(String),
+    #[error("Encoding {0} is already registered")]
+    DuplicateEncodingNameError(String),
+    #[error("Model {0} is already registered")]
+    DuplicateModelNameError(String),
+    #[error("{file}:{line}: invalid vocabulary line: {reason}")]
+    InvalidVocabLine {
+        file: String,
+        line: usize,
+        reason: String,
+    },
+    #[error("{file}: invalid vocabulary file: {reason}")]
+    InvalidVocabFile { file: String, reason: String },
+    #[error(
+        "vocab.bpe and encoder.json disagree on token {token:?}: \
+merges rank {merges_rank:?}, encoder.json rank {encoder_rank:?}"
+    )]
+    DataGymMismatchError {
+        token: Vec<u8>,
+        merges_rank: Option<usize>,
+        encoder_rank: Option<usize>,
+    },
+    #[error("Checksum mismatch for `{path}`: expected {expected}, got {actual}")]
+    ChecksumMismatchError {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("rank {rank} is used by both {first:?} and {second:?}")]
+    DuplicateRankError {
+        rank: usize,
+        first: Vec<u8>,
+        second: Vec<u8>,
+    },
+    #[error(
+        "mergeable ranks must be contiguous, {0} is neither a mergeable rank nor a special token"
+    )]
+    RankGapError(usize),
+    #[error("special token `{token}` reuses token value {rank}")]
+    SpecialTokenCollisionError { token: String, rank: usize },
+    #[error("expected a vocabulary of {expected} tokens, got {actual}")]
+    VocabSizeMismatchError { expected: usize, actual: usize },
+    #[error("missing `{0}` to build the encoding")]
+    MissingBuilderFieldError(&'static str),
     #[error("Stdio error: {0}")]
     IOError(#[from] io::Error),
+    #[cfg(feature = "remote")]
     #[error("Network error: {0}")]
     HTTPError(#[from] reqwest::Error),
+    #[error("Cannot load `{0}`: remote loading is disabled, enable the `remote` feature")]
+    RemoteDisabledError(String),
 }
 
 // TODO: these will likely be replaced by an API endpoint
-pub static MODEL_PREFIX_TO_ENCODING: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
-    HashMap::from([
+pub static MODEL_PREFIX_TO_ENCODING: Lazy<RwLock<HashMap<&str, &str>>> = Lazy::new(|| {
+    RwLock::new(HashMap::from([
+        // reasoning
+        ("o1-", "o200k_base"), // e.g., o1-mini, o1-preview, o1-2024-12-17
+        ("o3-", "o200k_base"), // e.g., o3-mini
         // chat
-        ("gpt-4-", "cl100k_base"), // e.g., gpt-4-0314, etc., plus gpt-4-32k
+        ("chatgpt-4o-", "o200k_base"),     // e.g., chatgpt-4o-latest
+        ("gpt-4o-", "o200k_base"),         // e.g., gpt-4o-2024-05-13, gpt-4o-mini
+        ("gpt-4-", "cl100k_base"),         // e.g., gpt-4-0314, etc., plus gpt-4-32k
         ("gpt-3.5-turbo-", "cl100k_base"), // e.g, gpt-3.5-turbo-0301, -0401, etc.
-        ("gpt-35-turbo", "cl100k_base"), // Azure deployment name
-    ])
+        ("gpt-35-turbo", "cl100k_base"),   // Azure deployment name
+    ]))
 });
 
-pub static MODEL_TO_ENCODING: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
-    HashMap::from([
+pub static MODEL_TO_ENCODING: Lazy<RwLock<HashMap<&str, &str>>> = Lazy::new(|| {
+    RwLock::new(HashMap::from([
+        // reasoning
+        ("o1", "o200k_base"),
+        ("o3", "o200k_base"),
         // chat
+        ("gpt-4o", "o200k_base"),
+        ("gpt-4o-mini", "o200k_base"),
         ("gpt-4", "cl100k_base"),
         ("gpt-3.5-turbo", "cl100k_base"),
         ("gpt-35-turbo", "cl100k_base"), // Azure deployment name
@@ -106,5 +205,5 @@ pub static MODEL_TO_ENCODING: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
         ("code-search-ada-code-001", "r50k_base"),
         // open source
         ("gpt2", "gpt2"),
-    ])
+    ]))
 });
@@ -1,104 +1,193 @@
-use crate::core::EncodingParam;
-use crate::load::{data_gym_to_mergeable_bpe_ranks, load_tiktoken_bpe};
+use crate::core::{EncodingParam, Result};
+#[allow(unused_imports)]
+use crate::load::{
+    data_gym_to_mergeable_bpe_ranks, load_tiktoken_bpe, parse_data_gym, parse_tiktoken_bpe,
+};
+use crate::model::EncodeError;
+use crate::vocab_files::*;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 const ENDOFTEXT: &str = "<|endoftext|>";
 const FIM_PREFIX: &str = "<|fim_prefix|>";
 const FIM_MIDDLE: &str = "<|fim_middle|>";
 const FIM_SUFFIX: &str = "<|fim_suffix|>";
 const ENDOFPROMPT: &str = "<|endofprompt|>";
+pub const IM_START: &str = "<|im_start|>";
+pub const IM_END: &str = "<|im_end|>";
+pub const IM_SEP: &str = "<|im_sep|>";
 
-#[allow(clippy::type_complexity)]
-static ENCODING_TO_CONSTRUCTOR: Lazy<
-    HashMap<&'static str, Box<dyn Fn() -> EncodingParam + Send + Sync>>,
-> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    m.insert(
-        "gpt2",
-        Box::new(gpt2) as Box<dyn Fn() -> EncodingParam + Send + Sync>,
-    );
-    m.insert(
-        "r50k_base",
-        Box::new(r50k_base) as Box<dyn Fn() -> EncodingParam + Send + Sync>,
-    );
-    m.insert(
-        "p50k_base",
-        Box::new(p50k_base) as Box<dyn Fn() -> EncodingParam + Send + Sync>,
-    );
-    m.insert(
-        "p50k_edit",
-        Box::new(p50k_edit) as Box<dyn Fn() -> EncodingParam + Send + Sync>,
-    );
-    m.insert(
-        "cl100k_base",
-        Box::new(cl100k_base) as Box<dyn Fn() -> EncodingParam + Send + Sync>,
-    );
-    m
-});
-
-#[allow(clippy::borrowed_box)]
-pub fn find_encoding_constructor(
-    encoding_name: &str,
-) -> Option<&Box<dyn Fn() -> EncodingParam + Send + Sync>> {
-    ENCODING_TO_CONSTRUCTOR.get(encoding_name)
+static ENCODING_TO_VOCAB_FILES: Lazy<HashMap<&'static str, Vec<&'static VocabFile>>> =
+    Lazy::new(|| {
+        HashMap::from([
+            ("gpt2", vec![&GPT2_VOCAB_BPE, &GPT2_ENCODER_JSON]),
+            ("r50k_base", vec![&R50K_BASE]),
+            ("p50k_base", vec![&P50K_BASE]),
+            ("p50k_edit", vec![&P50K_BASE]),
+            ("cl100k_base", vec![&CL100K_BASE]),
+            ("cl100k_im", vec![&CL100K_BASE]),
+            ("o200k_base", vec![&O200K_BASE]),
+        ])
+    });
+
+pub type EncodingConstructor = Arc<dyn Fn() -> Result<EncodingParam> + Send + Sync>;
+
+static ENCODING_TO_CONSTRUCTOR: Lazy<RwLock<HashMap<&'static str, EncodingConstructor>>> =
+    Lazy::new(|| {
+        let mut m = HashMap::new();
+        m.insert("gpt2", Arc::new(gpt2) as EncodingConstructor);
+        m.insert("r50k_base", Arc::new(r50k_base) as EncodingConstructor);
+        m.insert("p50k_base", Arc::new(p50k_base) as EncodingConstructor);
+        m.insert("p50k_edit", Arc::new(p50k_edit) as EncodingConstructor);
+        m.insert("cl100k_base", Arc::new(cl100k_base) as EncodingConstructor);
+        m.insert("o200k_base", Arc::new(o200k_base) as EncodingConstructor);
+        RwLock::new(m)
+    });
+
+/// A built-in encoding made of a base encoding plus special tokens
+struct DerivedEncoding {
+    name: &'static str,
+    base_name: &'static str,
+    special_tokens: fn() -> HashMap<String, usize>,
+}
+
+static DERIVED_ENCODINGS: &[DerivedEncoding] = &[DerivedEncoding {
+    name: "cl100k_im",
+    base_name: "cl100k_base",
+    special_tokens: cl100k_im_special_tokens,
+}];
+
+pub fn find_encoding_constructor(encoding_name: &str) -> Option<EncodingConstructor> {
+    ENCODING_TO_CONSTRUCTOR
+        .read()
+        .unwrap()
+        .get(encoding_name)
+        .cloned()
+}
+
+/// Makes an encoding available to `get_encoding` under `encoding_name`.
+///
+/// `constructor` is called at most once, by the first successful `get_encoding` call.
+/// Returns Err if an encoding with the same name already exists.
+pub fn register_encoding<F>(encoding_name: &str, constructor: F) -> Result<()>
+where
+    F: Fn() -> Result<EncodingParam> + Send + Sync + 'static,
+{
+    let mut constructors = ENCODING_TO_CONSTRUCTOR.write().unwrap();
+    if constructors.contains_key(encoding_name) || find_derived_encoding(encoding_name).is_some() {
+        return Err(EncodeError::DuplicateEncodingNameError(
+            encoding_name.to_string(),
+        ));
+    }
+    // Registered names live as long as the process, like the built-in ones
+    let encoding_name: &'static str = Box::leak(encoding_name.to_string().into_boxed_str());
+    constructors.insert(encoding_name, Arc::new(constructor));
+    Ok(())
+}
+
+/// Returns the remote vocabulary files an encoding is built from
+pub fn find_vocab_files(encoding_name: &str) -> Option<&'static [&'static VocabFile]> {
+    ENCODING_TO_VOCAB_FILES
+        .get(encoding_name)
+        .map(|files| files.as_slice())
+}
+
+/// Returns every remote vocabulary file, without duplicates
+pub fn all_vocab_files() -> Vec<&'static VocabFile> {
+    let mut files: Vec<&'static VocabFile> = vec![];
+    for &file in ENCODING_TO_VOCAB_FILES.values().flatten() {
+        if !files.iter().any(|f| f.url == file.url) {
+            files.push(file);
+        }
+    }
+    files
 }
 
-/// List available encodings by name
-pub fn list_encoding_names<'a>() -> Vec<&'a str> {
-    ENCODING_TO_CONSTRUCTOR.keys().copied().collect()
+/// List available encodings by name, including registered ones
+pub fn list_encoding_names() -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = ENCODING_TO_CONSTRUCTOR
+        .read()
+        .unwrap()
+        .keys()
+        .copied()
+        .collect();
+    names.extend(DERIVED_ENCODINGS.iter().map(|derived| derived.name));
+    names
 }
 
-fn gpt2() -> EncodingParam {
+fn gpt2() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-gpt2")]
+    let mergeable_ranks = parse_data_gym(
+        include_str!(concat!(env!("OUT_DIR"), "/gpt2/vocab.bpe")),
+        GPT2_VOCAB_BPE.asset,
+        include_str!(concat!(env!("OUT_DIR"), "/gpt2/encoder.json")),
+        GPT2_ENCODER_JSON.asset,
+    )?;
+    #[cfg(not(feature = "embedded-gpt2"))]
     let mergeable_ranks = data_gym_to_mergeable_bpe_ranks(
-        "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
-        "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
-    );
+        GPT2_VOCAB_BPE.url,
+        GPT2_ENCODER_JSON.url,
+        Some(GPT2_VOCAB_BPE.hash),
+        Some(GPT2_ENCODER_JSON.hash),
+    )?;
     let special_tokens = vec![(ENDOFTEXT.to_string(), 50256usize)];
 
-    EncodingParam::new(
+    Ok(EncodingParam::new(
         "gpt2".to_string(),
         r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
         mergeable_ranks,
         special_tokens.into_iter().collect(),
         Some(50257),
-    )
+    ))
 }
 
-fn r50k_base() -> EncodingParam {
-    let mergeable_ranks = load_tiktoken_bpe(
-        "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
-    );
+fn r50k_base() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-r50k")]
+    let mergeable_ranks = parse_tiktoken_bpe(
+        include_str!(concat!(env!("OUT_DIR"), "/r50k_base.tiktoken")),
+        R50K_BASE.asset,
+    )?;
+    #[cfg(not(feature = "embedded-r50k"))]
+    let mergeable_ranks = load_tiktoken_bpe(R50K_BASE.url, Some(R50K_BASE.hash))?;
     let special_tokens = vec![(ENDOFTEXT.to_string(), 50256usize)];
 
-    EncodingParam::new(
+    Ok(EncodingParam::new(
         "r50k_base".to_string(),
         r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
         mergeable_ranks,
         special_tokens.into_iter().collect(),
         Some(50257),
-    )
+    ))
 }
 
-fn p50k_base() -> EncodingParam {
-    let mergeable_ranks = load_tiktoken_bpe(
-        "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
-    );
+fn p50k_base() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-p50k")]
+    let mergeable_ranks = parse_tiktoken_bpe(
+        include_str!(concat!(env!("OUT_DIR"), "/p50k_base.tiktoken")),
+        P50K_BASE.asset,
+    )?;
+    #[cfg(not(feature = "embedded-p50k"))]
+    let mergeable_ranks = load_tiktoken_bpe(P50K_BASE.url, Some(P50K_BASE.hash))?;
     let special_tokens = vec![(ENDOFTEXT.to_string(), 50256usize)];
 
-    EncodingParam::new(
+    Ok(EncodingParam::new(
         "p50k_base".to_string(),
         r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
         mergeable_ranks,
         special_tokens.into_iter().collect(),
         Some(50281),
-    )
+    ))
 }
 
-fn p50k_edit() -> EncodingParam {
-    let mergeable_ranks = load_tiktoken_bpe(
-        "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
-    );
+fn p50k_edit() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-p50k")]
+    let mergeable_ranks = parse_tiktoken_bpe(
+        include_str!(concat!(env!("OUT_DIR"), "/p50k_base.tiktoken")),
+        P50K_BASE.asset,
+    )?;
+    #[cfg(not(feature = "embedded-p50k"))]
+    let mergeable_ranks = load_tiktoken_bpe(P50K_BASE.url, Some(P50K_BASE.hash))?;
     let special_tokens = vec![
         (ENDOFTEXT.to_string(), 50256usize),
         (FIM_PREFIX.to_string(), 50281usize),
@@ -106,39 +195,181 @@ fn p50k_edit() -> EncodingParam {
         (FIM_SUFFIX.to_string(), 50283usize),
     ];
 
-    EncodingParam::new(
+    Ok(EncodingParam::new(
         "p50k_edit".to_string(),
         r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+".to_string(),
         mergeable_ranks,
         special_tokens.into_iter().collect(),
         None,
-    )
+    ))
 }
 
-fn cl100k_base() -> EncodingParam {
-    let mergeable_ranks = load_tiktoken_bpe(
-        "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
-    );
-    let special_tokens = vec![
-        (ENDOFTEXT.to_string(), 100257usize),
-        (FIM_PREFIX.to_string(), 100258usize),
-        (FIM_MIDDLE.to_string(), 100259usize),
-        (FIM_SUFFIX.to_string(), 100260usize),
-        (ENDOFPROMPT.to_string(), 100276usize),
-    ];
+fn cl100k_base() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-cl100k")]
+    let mergeable_ranks = parse_tiktoken_bpe(
+        include_str!(concat!(env!("OUT_DIR"), "/cl100k_base.tiktoken")),
+        CL100K_BASE.asset,
+    )?;
+    #[cfg(not(feature = "embedded-cl100k"))]
+    let mergeable_ranks = load_tiktoken_bpe(CL100K_BASE.url, Some(CL100K_BASE.hash))?;
 
-    EncodingParam::new(
+    Ok(EncodingParam::new(
         "cl100k_base".to_string(),
-        r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+".to_string(),
+        CL100K_PAT_STR.to_string(),
         mergeable_ranks,
-        special_tokens.into_iter().collect(),
-        None
-    )
+        cl100k_special_tokens(),
+        None,
+    ))
+}
+
+const CL100K_PAT_STR: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+fn cl100k_special_tokens() -> HashMap<String, usize> {
+    HashMap::from([
+        (ENDOFTEXT.to_string(), 100257),
+        (FIM_PREFIX.to_string(), 100258),
+        (FIM_MIDDLE.to_string(), 100259),
+        (FIM_SUFFIX.to_string(), 100260),
+        (ENDOFPROMPT.to_string(), 100276),
+    ])
+}
+
+/// The ChatML special tokens that cl100k_im adds to cl100k_base, see `ChatTemplate`
+fn cl100k_im_special_tokens() -> HashMap<String, usize> {
+    HashMap::from([
+        (IM_START.to_string(), 100264),
+        (IM_END.to_string(), 100265),
+        (IM_SEP.to_string(), 100266),
+    ])
+}
+
+/// Returns the encoding and the extra special tokens a built-in encoding is made of,
+/// so `get_encoding` can share the ranks of the base encoding.
+pub fn find_derived_encoding(
+    encoding_name: &str,
+) -> Option<(&'static str, HashMap<String, usize>)> {
+    DERIVED_ENCODINGS
+        .iter()
+        .find(|derived| derived.name == encoding_name)
+        .map(|derived| (derived.base_name, (derived.special_tokens)()))
+}
+
+fn o200k_base() -> Result<EncodingParam> {
+    #[cfg(feature = "embedded-o200k")]
+    let mergeable_ranks = parse_tiktoken_bpe(
+        include_str!(concat!(env!("OUT_DIR"), "/o200k_base.tiktoken")),
+        O200K_BASE.asset,
+    )?;
+    #[cfg(not(feature = "embedded-o200k"))]
+    let mergeable_ranks = load_tiktoken_bpe(O200K_BASE.url, Some(O200K_BASE.hash))?;
+
+    Ok(EncodingParam::new(
+        "o200k_base".to_string(),
+        o200k_pat_str(),
+        mergeable_ranks,
+        o200k_special_tokens(),
+        None,
+    ))
+}
+
+fn o200k_special_tokens() -> HashMap<String, usize> {
+    HashMap::from([
+        (ENDOFTEXT.to_string(), 199999),
+        (ENDOFPROMPT.to_string(), 200018),
+    ])
+}
+
+pub fn o200k_pat_str() -> String {
+    // This regex could be made more efficient
+    [
+        r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+        r"\p{N}{1,3}",
+        r" ?[^\s\p{L}\p{N}]+[\r\n/]*",
+        r"\s*[\r\n]+",
+        r"\s+(?!\S)",
+        r"\s+",
+    ]
+    .join("|")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::EncodingBuilder;
+    use crate::test_util::byte_ranks;
+    use crate::{AllowedSpecial, ChatMessage, ChatTemplate, DisallowedSpecial};
+
+    #[test]
+    fn test_o200k_base_pattern() {
+        let regex = fancy_regex::Regex::new(&o200k_pat_str()).unwrap();
+        let pieces: Vec<&str> = regex
+            .find_iter("Hello WORLDs don't 12345 CamelCase\n\n  ok/\n")
+            .map(|m| m.unwrap().as_str())
+            .collect();
+        assert_eq!(
+            pieces,
+            vec![
+                "Hello", " WORLDs", " don't", " ", "123", "45", " Camel", "Case", "\n\n", " ",
+                " ok", "/\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_o200k_base_layout() {
+        // byte ranks and a few merges, with the special tokens of o200k_base above them
+        let mut ranks = byte_ranks();
+        for merge in ["He", "ll", "llo", " W"] {
+            let rank = ranks.len();
+            ranks.insert(merge.as_bytes().to_vec(), rank);
+        }
+        let enc = EncodingBuilder::new()
+            .name("o200k_like")
+            .pattern(o200k_pat_str())
+            .mergeable_ranks(ranks)
+            .special_tokens(o200k_special_tokens())
+            .build()
+            .unwrap();
+        assert_eq!(enc.n_vocab(), 200019);
+        assert_eq!(
+            enc.encode(
+                "Hello WORLD<|endofprompt|>12345<|endoftext|>",
+                AllowedSpecial::All,
+                DisallowedSpecial::All
+            )
+            .unwrap(),
+            vec![256, 258, 259, 79, 82, 76, 68, 200018, 49, 50, 51, 52, 53, 199999]
+        );
+    }
+
+    #[test]
+    fn test_cl100k_im_layout() {
+        // byte ranks with the special tokens of cl100k_base, derived like get_encoding does
+        let base = EncodingBuilder::new()
+            .name("cl100k_like")
+            .pattern(CL100K_PAT_STR)
+            .mergeable_ranks(byte_ranks())
+            .special_tokens(cl100k_special_tokens())
+            .build()
+            .unwrap();
+        let (base_name, special_tokens) = find_derived_encoding("cl100k_im").unwrap();
+        assert_eq!(base_name, "cl100k_base");
+        let enc = base
+            .extend_special_tokens("cl100k_im", special_tokens)
+            .unwrap();
+        assert_eq!(enc.n_vocab(), 100277);
+        assert_eq!(enc.special_tokens_set().len(), 8);
+
+        let template = ChatTemplate::new(&enc).unwrap();
+        assert_eq!(
+            template.render_for_completion(&[ChatMessage::new("user", "hi")]),
+            vec![
+                100264, 117, 115, 101, 114, 100266, 104, 105, 100265, 100264, 97, 115, 115, 105,
+                115, 116, 97, 110, 116, 100266
+            ]
+        );
+    }
 
     #[test]
     fn test_list_encoding_names() {
@@ -147,7 +378,15 @@ mod tests {
 
         assert_eq!(
             res,
-            vec!["cl100k_base", "gpt2", "p50k_base", "p50k_edit", "r50k_base"]
+            vec![
+                "cl100k_base",
+                "cl100k_im",
+                "gpt2",
+                "o200k_base",
+                "p50k_base",
+                "p50k_edit",
+                "r50k_base"
+            ]
         );
     }
 }
@@ -0,0 +1,360 @@
+use crate::core::{bytes_to_string, Encoding, Result};
+use crate::model::DecodeMode;
+use std::io::Read;
+use std::vec;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+// Held back text longer than this is only tokenized again once it doubled
+const RESCAN_MIN_LEN: usize = 64 * 1024;
+
+/// Encodes a text received in parts, like `Encoding::encode_ordinary` would encode the
+/// whole text, see `Encoding::streaming_encoder`.
+///
+/// Only the end of the text that may still be tokenized differently is kept in memory.
+#[derive(Debug)]
+pub struct StreamingEncoder<'a> {
+    encoding: &'a Encoding,
+    buffer: Vec<u8>,
+    // the length of the start of `buffer` already checked to be valid UTF-8
+    valid_up_to: usize,
+    // `buffer` is tokenized again once `valid_up_to` reaches this, so a long piece or run
+    // of whitespace isn't scanned on every call, which would make feeding quadratic
+    rescan_at: usize,
+}
+
+impl<'a> StreamingEncoder<'a> {
+    pub(crate) fn new(encoding: &'a Encoding) -> Self {
+        StreamingEncoder {
+            encoding,
+            buffer: vec![],
+            valid_up_to: 0,
+            rescan_at: 0,
+        }
+    }
+
+    /// Adds the next bytes of the text, returns the tokens that can't change anymore.
+    ///
+    /// Characters may be split between calls. Returns Err if the bytes are not valid UTF-8.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Vec<usize>> {
+        self.buffer.extend_from_slice(bytes);
+        match std::str::from_utf8(&self.buffer[self.valid_up_to..]) {
+            Ok(_) => self.valid_up_to = self.buffer.len(),
+            // the buffer ends with an incomplete character
+            Err(e) if e.error_len().is_none() => self.valid_up_to += e.valid_up_to(),
+            Err(_) => return Err(String::from_utf8(self.buffer.clone()).unwrap_err().into()),
+        };
+        if self.valid_up_to < self.rescan_at {
+            return Ok(vec![]);
+        }
+        // Safe as the bytes were just checked
+        let text = std::str::from_utf8(&self.buffer[..self.valid_up_to]).unwrap();
+
+        let (tokens, end) = self.encoding.encode_ordinary_stable_prefix(text);
+        self.buffer.drain(..end);
+        self.valid_up_to -= end;
+        self.rescan_at = if self.valid_up_to > RESCAN_MIN_LEN {
+            2 * self.valid_up_to
+        } else {
+            0
+        };
+        Ok(tokens)
+    }
+
+    /// Returns the tokens of the rest of the text.
+    ///
+    /// Returns Err if the text ends with an incomplete character.
+    pub fn finish(self) -> Result<Vec<usize>> {
+        let text = String::from_utf8(self.buffer)?;
+        Ok(self.encoding.encode_ordinary(&text))
+    }
+}
+
+/// Decodes tokens received one by one, see `Encoding::streaming_decoder`.
+#[derive(Debug)]
+pub struct StreamingDecoder<'a> {
+    encoding: &'a Encoding,
+    mode: DecodeMode,
+    // the start of a character whose remaining bytes are in the next tokens
+    buffer: Vec<u8>,
+}
+
+impl<'a> StreamingDecoder<'a> {
+    pub(crate) fn new(encoding: &'a Encoding, mode: DecodeMode) -> Self {
+        StreamingDecoder {
+            encoding,
+            mode,
+            buffer: vec![],
+        }
+    }
+
+    /// Decodes the next token, returns the text it completes, if any.
+    ///
+    /// Returns Err if the token doesn't exist, or if its bytes are invalid UTF-8 with
+    /// `DecodeMode::Strict`; the decoder can still be used afterwards.
+    pub fn push(&mut self, token: usize) -> Result<Option<String>> {
+        self.buffer
+            .extend(self.encoding.decode_single_token_bytes(token)?);
+
+        let end = incomplete_char_start(&self.buffer);
+        if end == 0 {
+            return Ok(None);
+        }
+        let bytes: Vec<u8> = self.buffer.drain(..end).collect();
+        bytes_to_string(bytes, self.mode).map(Some)
+    }
+
+    /// Returns the text of the bytes left by an incomplete character, emptying the decoder.
+    ///
+    /// Returns Err if there are any with `DecodeMode::Strict`.
+    pub fn flush(&mut self) -> Result<String> {
+        bytes_to_string(std::mem::take(&mut self.buffer), self.mode)
+    }
+}
+
+/// Returns where the incomplete character `bytes` end with starts, or `bytes.len()`
+fn incomplete_char_start(bytes: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match std::str::from_utf8(&bytes[start..]) {
+            Ok(_) => return bytes.len(),
+            Err(e) => match e.error_len() {
+                Some(len) => start += e.valid_up_to() + len,
+                None => return start + e.valid_up_to(),
+            },
+        }
+    }
+}
+
+/// Iterator returned by `Encoding::encode_reader`
+pub(crate) struct ReaderTokens<'a, R> {
+    reader: R,
+    encoder: Option<StreamingEncoder<'a>>,
+    tokens: vec::IntoIter<usize>,
+    buf: Vec<u8>,
+}
+
+impl<'a, R: Read> ReaderTokens<'a, R> {
+    pub(crate) fn new(encoding: &'a Encoding, reader: R) -> Self {
+        ReaderTokens {
+            reader,
+            encoder: Some(StreamingEncoder::new(encoding)),
+            tokens: vec![].into_iter(),
+            buf: vec![0; READ_BUFFER_SIZE],
+        }
+    }
+
+    /// Reads the next tokens, returns false once the reader is exhausted
+    fn read_tokens(&mut self) -> Result<bool> {
+        let mut encoder = match self.encoder.take() {
+            Some(encoder) => encoder,
+            None => return Ok(false),
+        };
+
+        let n = loop {
+            match self.reader.read(&mut self.buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+        let tokens = if n == 0 {
+            encoder.finish()?
+        } else {
+            let tokens = encoder.feed(&self.buf[..n])?;
+            self.encoder = Some(encoder);
+            tokens
+        };
+        self.tokens = tokens.into_iter();
+        Ok(true)
+    }
+}
+
+impl<'a, R: Read> Iterator for ReaderTokens<'a, R> {
+    type Item = Result<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.tokens.next() {
+                return Some(Ok(token));
+            }
+            match self.read_tokens() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                // Stop at the first error
+                Err(e) => {
+                    self.encoder = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::EncodingBuilder;
+    use crate::model::EncodeError;
+    use crate::openai_public::o200k_pat_str;
+    use crate::test_util::{byte_ranks, Rng};
+
+    const GPT2_PAT_STR: &str =
+        r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+    const CL100K_PAT_STR: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+    /// Bytes plus a few merges, enough to exercise the merge loop
+    fn encoding(pat_str: &str) -> Encoding {
+        let mut ranks = byte_ranks();
+        for merge in [
+            "ab", "abc", " a", "  ", "\n\n", "'s", "12", "é€", "don", " don",
+        ] {
+            let rank = ranks.len();
+            ranks.insert(merge.as_bytes().to_vec(), rank);
+        }
+        EncodingBuilder::new()
+            .name("streaming")
+            .pattern(pat_str)
+            .mergeable_ranks(ranks)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_streaming_encoder_matches_encode_ordinary() {
+        let parts = [
+            "a", "b", "c", "Z", "don", "t", "ll", " ", "  ", "\t", "\n", "\r\n", "\n\n", "'", "'s",
+            "'LL", "1", "23", "4567", "!", "?!", "/", ".", "é", "€", "👍", "我", "\u{a0}",
+        ];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for pat_str in [GPT2_PAT_STR, CL100K_PAT_STR, &o200k_pat_str()] {
+            let enc = encoding(pat_str);
+            for _ in 0..300 {
+                let text: String = (0..rng.next(40))
+                    .map(|_| parts[rng.next(parts.len())])
+                    .collect();
+                let bytes = text.as_bytes();
+
+                let mut encoder = enc.streaming_encoder();
+                let mut tokens = vec![];
+                let mut start = 0;
+                while start < bytes.len() {
+                    let end = (start + 1 + rng.next(6)).min(bytes.len());
+                    tokens.extend(encoder.feed(&bytes[start..end]).unwrap());
+                    start = end;
+                }
+                tokens.extend(encoder.finish().unwrap());
+                assert_eq!(tokens, enc.encode_ordinary(&text), "{:?}", text);
+
+                let tokens: Result<Vec<usize>> = enc.encode_reader(bytes).collect();
+                assert_eq!(tokens.unwrap(), enc.encode_ordinary(&text), "{:?}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_encoder_holds_back() {
+        let enc = encoding(CL100K_PAT_STR);
+        let mut encoder = enc.streaming_encoder();
+        assert_eq!(encoder.feed(b"hello wor").unwrap(), Vec::<usize>::new());
+        assert_eq!(
+            encoder.feed(b"ld and ").unwrap(),
+            enc.encode_ordinary("hello world")
+        );
+        // the incomplete character is held back
+        assert_eq!(
+            encoder.feed(b"x \xe2\x82").unwrap(),
+            enc.encode_ordinary(" and")
+        );
+        assert_eq!(encoder.buffer, b" x \xe2\x82");
+        assert!(matches!(
+            encoder.finish(),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+
+        let mut encoder = enc.streaming_encoder();
+        assert!(matches!(
+            encoder.feed(b"a\xffb"),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+        let tokens: Vec<Result<usize>> = enc.encode_reader(&b"ab \xff"[..]).collect();
+        assert!(matches!(
+            tokens.as_slice(),
+            [Err(EncodeError::ConvertStringError(_))]
+        ));
+    }
+
+    #[test]
+    fn test_streaming_encoder_long_piece() {
+        let enc = encoding(CL100K_PAT_STR);
+        // fancy_regex can't match pieces much longer than this
+        for text in [
+            format!("{} end", "ab".repeat(256 * 1024)),
+            format!("x{}y", " \n".repeat(192 * 1024)),
+        ] {
+            let mut encoder = enc.streaming_encoder();
+            let mut tokens = vec![];
+            let mut n_scans = 0;
+            for part in text.as_bytes().chunks(4096) {
+                if encoder.valid_up_to + part.len() >= encoder.rescan_at {
+                    n_scans += 1;
+                }
+                tokens.extend(encoder.feed(part).unwrap());
+            }
+            // once longer than RESCAN_MIN_LEN, the held back end is only scanned again
+            // after it doubled, rather than on each of the 128 calls
+            assert!(n_scans < 24, "{}", n_scans);
+            tokens.extend(encoder.finish().unwrap());
+            assert_eq!(tokens, enc.encode_ordinary(&text));
+        }
+    }
+
+    #[test]
+    fn test_streaming_decoder() {
+        let enc = encoding(CL100K_PAT_STR);
+        let text = "ab 👍 é€ 我!";
+        for mode in [DecodeMode::Strict, DecodeMode::Replace] {
+            let mut decoder = enc.streaming_decoder(mode);
+            let mut pushed = vec![];
+            for token in enc.encode_ordinary(text) {
+                if let Some(text) = decoder.push(token).unwrap() {
+                    pushed.push(text);
+                }
+            }
+            assert_eq!(decoder.flush().unwrap(), "");
+            assert_eq!(pushed.concat(), text);
+            // multibyte characters are only returned once complete
+            assert!(pushed
+                .iter()
+                .all(|text| !text.is_empty() && !text.contains('\u{fffd}')));
+        }
+
+        let mut decoder = enc.streaming_decoder(DecodeMode::Replace);
+        assert_eq!(decoder.push(0xf0).unwrap(), None);
+        assert_eq!(decoder.push(0x9f).unwrap(), None);
+        // an invalid byte ends the incomplete character
+        assert_eq!(
+            decoder.push(b'a' as usize).unwrap(),
+            Some("\u{fffd}a".to_string())
+        );
+        assert_eq!(decoder.push(0xe2).unwrap(), None);
+        assert_eq!(decoder.flush().unwrap(), "\u{fffd}");
+        assert_eq!(decoder.flush().unwrap(), "");
+
+        let mut decoder = enc.streaming_decoder(DecodeMode::Strict);
+        assert!(matches!(
+            decoder.push(0x80),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+        assert_eq!(decoder.push(0xe2).unwrap(), None);
+        assert!(matches!(
+            decoder.flush(),
+            Err(EncodeError::ConvertStringError(_))
+        ));
+        assert!(matches!(
+            decoder.push(1_000_000),
+            Err(EncodeError::TokenNotFoundError {
+                token: 1_000_000,
+                position: None
+            })
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+//! Helpers shared by the unit tests
+
+use crate::core::EncodingBuilder;
+use crate::Encoding;
+use std::collections::HashMap;
+
+/// A small xorshift generator, to avoid depending on `rand`
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// The 256 single bytes, ranked by their value
+pub(crate) fn byte_ranks() -> HashMap<Vec<u8>, usize> {
+    (0..=255u8).map(|b| (vec![b], b as usize)).collect()
+}
+
+/// A byte-level encoding without merges or special tokens, splitting on whitespace
+pub(crate) fn byte_level() -> Encoding {
+    EncodingBuilder::new()
+        .name("byte_level")
+        .pattern(r"\S+|\s+")
+        .mergeable_ranks(byte_ranks())
+        .build()
+        .unwrap()
+}
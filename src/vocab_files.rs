@@ -0,0 +1,52 @@
+//! The remote vocabulary files of the public encodings.
+//!
+//! build.rs includes this file too, to check the files of the `embedded-*` features.
+
+/// A remote vocabulary file along with its expected SHA-256
+pub struct VocabFile {
+    pub url: &'static str,
+    pub hash: &'static str,
+    /// The path of the file in the assets directory of the `embedded-*` features
+    #[cfg_attr(
+        not(any(
+            feature = "embedded-gpt2",
+            feature = "embedded-r50k",
+            feature = "embedded-p50k",
+            feature = "embedded-cl100k",
+            feature = "embedded-o200k"
+        )),
+        allow(dead_code)
+    )]
+    pub asset: &'static str,
+}
+
+pub const GPT2_VOCAB_BPE: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/vocab.bpe",
+    hash: "1ce1664773c50f3e0cc8842619a93edc4624525b728b188a9e0be33b7726adc5",
+    asset: "gpt2/vocab.bpe",
+};
+pub const GPT2_ENCODER_JSON: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/gpt-2/encodings/main/encoder.json",
+    hash: "196139668be63f3b5d6574427317ae82f02bfc7cbf2a1b4d2bb5b44d8a8bd2f7",
+    asset: "gpt2/encoder.json",
+};
+pub const R50K_BASE: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/encodings/r50k_base.tiktoken",
+    hash: "306cd27f03c1a714eca7108e03d66b7dc042abe8c258b44c199a7ed9838dd930",
+    asset: "r50k_base.tiktoken",
+};
+pub const P50K_BASE: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/encodings/p50k_base.tiktoken",
+    hash: "94b5ca7dff4d00767bc256fdd1b27e5b17361d7b8a5f968547f9f23eb70d2069",
+    asset: "p50k_base.tiktoken",
+};
+pub const CL100K_BASE: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/encodings/cl100k_base.tiktoken",
+    hash: "223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7",
+    asset: "cl100k_base.tiktoken",
+};
+pub const O200K_BASE: VocabFile = VocabFile {
+    url: "https://openaipublic.blob.core.windows.net/encodings/o200k_base.tiktoken",
+    hash: "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d",
+    asset: "o200k_base.tiktoken",
+};
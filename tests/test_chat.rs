@@ -0,0 +1,77 @@
+use tiktoken_rust as tt;
+
+// From https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+fn example_messages() -> Vec<tt::ChatMessage> {
+    vec![
+        tt::ChatMessage::new(
+            "system",
+            "You are a helpful, pattern-following assistant that translates corporate jargon into plain English.",
+        ),
+        tt::ChatMessage::new("system", "New synergies will help drive top-line growth.")
+            .with_name("example_user"),
+        tt::ChatMessage::new("system", "Things working well together will increase revenue.")
+            .with_name("example_assistant"),
+        tt::ChatMessage::new(
+            "system",
+            "Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage.",
+        )
+        .with_name("example_user"),
+        tt::ChatMessage::new(
+            "system",
+            "Let's talk later when we're less busy about how to do better.",
+        )
+        .with_name("example_assistant"),
+        tt::ChatMessage::new(
+            "user",
+            "This late pivot means we don't have time to boil the ocean for the client deliverable.",
+        ),
+    ]
+}
+
+#[test]
+fn test_num_tokens_from_messages() -> tt::Result<()> {
+    let messages = example_messages();
+    for (model, expected) in [
+        ("gpt-3.5-turbo-0301", 127),
+        ("gpt-3.5-turbo-0613", 129),
+        ("gpt-3.5-turbo", 129),
+        ("gpt-4-0314", 129),
+        ("gpt-4-0613", 129),
+        ("gpt-4", 129),
+    ] {
+        let enc = tt::encoding_for_model(model)?;
+        assert_eq!(
+            tt::num_tokens_from_messages(&enc, model, &messages)?,
+            expected,
+            "{}",
+            model
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_chat_template() -> tt::Result<()> {
+    let enc = tt::get_encoding("cl100k_im")?;
+    assert_eq!(enc.encode_single_token(b"<|im_start|>")?, 100264);
+    assert_eq!(enc.encode_single_token(b"<|im_end|>")?, 100265);
+    assert_eq!(enc.encode_single_token(b"<|im_sep|>")?, 100266);
+
+    let template = tt::ChatTemplate::new(&enc)?;
+    let tokens = template.render_for_completion(&example_messages()[..2]);
+    assert_eq!(
+        enc.decode(&tokens, tt::DecodeMode::Strict)?,
+        "<|im_start|>system<|im_sep|>You are a helpful, pattern-following assistant that translates \
+corporate jargon into plain English.<|im_end|><|im_start|>system name=example_user<|im_sep|>\
+New synergies will help drive top-line growth.<|im_end|><|im_start|>assistant<|im_sep|>"
+    );
+    // 3 markers per message, plus the header of the reply
+    assert_eq!(tokens.iter().filter(|&&t| t >= 100257).count(), 8);
+
+    let enc = tt::get_encoding("cl100k_base")?;
+    assert!(matches!(
+        tt::ChatTemplate::new(&enc),
+        Err(tt::EncodeError::MissingSpecialTokenError(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use tiktoken_rust as tt;
+
+fn tiny_param(name: &str) -> tt::EncodingParam {
+    let mut ranks: HashMap<Vec<u8>, usize> = (0..=255u8).map(|b| (vec![b], b as usize)).collect();
+    ranks.insert(b"hi".to_vec(), 256);
+    tt::EncodingParam::new(
+        name.to_string(),
+        r"\S+|\s+".to_string(),
+        ranks,
+        HashMap::from([("<|endoftext|>".to_string(), 257)]),
+        Some(258),
+    )
+}
+
+#[test]
+fn test_register_encoding() -> tt::Result<()> {
+    tt::register_encoding("tiny_registered", || Ok(tiny_param("tiny_registered")))?;
+    assert!(tt::list_encoding_names().contains(&"tiny_registered"));
+    assert!(matches!(
+        tt::register_encoding("tiny_registered", || Ok(tiny_param("other"))),
+        Err(tt::EncodeError::DuplicateEncodingNameError(_))
+    ));
+    assert!(matches!(
+        tt::register_encoding("cl100k_base", || Ok(tiny_param("cl100k_base"))),
+        Err(tt::EncodeError::DuplicateEncodingNameError(_))
+    ));
+    assert!(matches!(
+        tt::register_encoding("cl100k_im", || Ok(tiny_param("cl100k_im"))),
+        Err(tt::EncodeError::DuplicateEncodingNameError(_))
+    ));
+
+    let enc = tt::get_encoding("tiny_registered")?;
+    assert_eq!(enc.name(), "tiny_registered");
+    assert_eq!(enc.encode_ordinary("hi !"), [256, 32, 33]);
+
+    Ok(())
+}
+
+#[test]
+fn test_register_model() -> tt::Result<()> {
+    tt::register_encoding("tiny_models", || Ok(tiny_param("tiny_models")))?;
+
+    tt::register_model("tiny-model", "tiny_models")?;
+    assert_eq!(tt::encoding_for_model("tiny-model")?.name(), "tiny_models");
+    assert!(matches!(
+        tt::register_model("tiny-model", "tiny_models"),
+        Err(tt::EncodeError::DuplicateModelNameError(_))
+    ));
+    assert!(matches!(
+        tt::register_model("gpt-4", "tiny_models"),
+        Err(tt::EncodeError::DuplicateModelNameError(_))
+    ));
+    assert!(matches!(
+        tt::register_model("other-model", "unknown_encoding"),
+        Err(tt::EncodeError::EncodingNameError(_))
+    ));
+
+    // the longest prefix wins over the built-in `gpt-4o-`
+    tt::register_model_prefix("gpt-4o-tiny-", "tiny_models")?;
+    assert_eq!(
+        tt::encoding_for_model("gpt-4o-tiny-2024")?.name(),
+        "tiny_models"
+    );
+    assert!(matches!(
+        tt::register_model_prefix("gpt-4o-", "tiny_models"),
+        Err(tt::EncodeError::DuplicateModelNameError(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_register_concurrently() {
+    let results: Vec<tt::Result<()>> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..8)
+            .map(|_| s.spawn(|| tt::register_encoding("tiny_racy", || Ok(tiny_param("tiny_racy")))))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+}
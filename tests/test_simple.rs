@@ -12,7 +12,7 @@ fn test_simple() -> tt::Result<()> {
         vec![31373, 995]
     );
     assert_eq!(
-        enc.decode(&vec![31373, 995], tt::DecodeMode::Strict)?,
+        enc.decode(&[31373, 995], tt::DecodeMode::Strict)?,
         String::from("hello world")
     );
     assert_eq!(
@@ -34,7 +34,7 @@ fn test_simple() -> tt::Result<()> {
         vec![15339, 1917]
     );
     assert_eq!(
-        enc.decode(&vec![15339, 1917], tt::DecodeMode::Strict)?,
+        enc.decode(&[15339, 1917], tt::DecodeMode::Strict)?,
         String::from("hello world")
     );
     assert_eq!(
@@ -250,8 +250,25 @@ fn test_basic_encode() -> tt::Result<()> {
         )?,
         [15339, 1917]
     );
+    let enc = tt::get_encoding("o200k_base")?;
+    assert_eq!(
+        enc.encode(
+            "hello world",
+            tt::AllowedSpecial::All,
+            tt::DisallowedSpecial::All
+        )?,
+        [24912, 2375]
+    );
+    assert_eq!(
+        enc.encode(
+            "hello <|endoftext|>",
+            tt::AllowedSpecial::All,
+            tt::DisallowedSpecial::All
+        )?,
+        [24912, 220, 199999]
+    );
     // py: chr(133) = '\x85'
-    let case = b" \x850";
+    let _case = b" \x850";
     // FIXME: failed case
     // assert_eq!(
     //     enc.encode(case, tt::AllowedSpecial::All, tt::DisallowedSpecial::All)?,
@@ -332,6 +349,33 @@ fn test_encoding_for_model() -> tt::Result<()> {
     assert_eq!(enc.name(), "p50k_edit");
     let enc = tt::encoding_for_model("gpt-3.5-turbo-0301")?;
     assert_eq!(enc.name(), "cl100k_base");
+    let enc = tt::encoding_for_model("gpt-4o")?;
+    assert_eq!(enc.name(), "o200k_base");
+    let enc = tt::encoding_for_model("gpt-4o-2024-08-06")?;
+    assert_eq!(enc.name(), "o200k_base");
+    let enc = tt::encoding_for_model("gpt-4o-mini")?;
+    assert_eq!(enc.name(), "o200k_base");
+    let enc = tt::encoding_for_model("o1")?;
+    assert_eq!(enc.name(), "o200k_base");
+    let enc = tt::encoding_for_model("o1-preview")?;
+    assert_eq!(enc.name(), "o200k_base");
+    let enc = tt::encoding_for_model("o3-mini")?;
+    assert_eq!(enc.name(), "o200k_base");
+
+    Ok(())
+}
+
+#[test]
+fn test_get_encoding_shared() -> tt::Result<()> {
+    let enc = tt::get_encoding("cl100k_base")?;
+    assert!(std::sync::Arc::ptr_eq(
+        &enc,
+        &tt::get_encoding("cl100k_base")?
+    ));
+    assert!(std::sync::Arc::ptr_eq(
+        &enc,
+        &tt::encoding_for_model("gpt-4")?
+    ));
 
     Ok(())
 }
@@ -339,7 +383,7 @@ fn test_encoding_for_model() -> tt::Result<()> {
 #[test]
 fn test_decode_with_offsets() -> tt::Result<()> {
     let enc = tt::get_encoding("gpt2")?;
-    let (text, offsets) = enc.decode_with_offsets(&vec![31373, 995])?;
+    let (text, offsets) = enc.decode_with_offsets(&[31373, 995])?;
     assert_eq!(text, "hello world");
     assert_eq!(offsets, &[0, 5]);
 
@@ -348,6 +392,134 @@ fn test_decode_with_offsets() -> tt::Result<()> {
 
 #[test]
 fn test_basic_offsets() -> tt::Result<()> {
-    // TODO
+    let enc = tt::get_encoding("cl100k_base")?;
+
+    let prompt = "hello world<|endoftext|> green cow";
+    let tokens = enc.encode(prompt, tt::AllowedSpecial::All, tt::DisallowedSpecial::All)?;
+    let (text, offsets) = enc.decode_with_offsets(&tokens)?;
+    assert_eq!(text, prompt);
+    assert_eq!(offsets, &[0, 5, 11, 24, 30]);
+
+    // tokens starting in the middle of a character get the offset of that character
+    let prompt = "我非常渴望与人工智能一起工作 👍";
+    let tokens = enc.encode_ordinary(prompt);
+    for unit in [
+        tt::OffsetUnit::Byte,
+        tt::OffsetUnit::Char,
+        tt::OffsetUnit::Utf16,
+    ] {
+        let (text, offsets) = enc.decode_with_offsets_in(&tokens, tt::DecodeMode::Strict, unit)?;
+        assert_eq!(text, prompt);
+        let starts: Vec<usize> = enc
+            .encode_with_offsets(prompt, unit)
+            .into_iter()
+            .map(|(_, range)| range.start)
+            .collect();
+        assert_eq!(offsets.len(), starts.len());
+        assert!(offsets.windows(2).all(|w| w[0] <= w[1]));
+        assert!(offsets.iter().zip(&starts).all(|(o, s)| o <= s));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_encode_with_offsets() -> tt::Result<()> {
+    let enc = tt::get_encoding("cl100k_base")?;
+    let text = "hello 👍 world, 我非常渴望";
+    let tokens = enc.encode_ordinary(text);
+
+    let with_offsets = enc.encode_with_offsets(text, tt::OffsetUnit::Byte);
+    assert_eq!(
+        with_offsets
+            .iter()
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>(),
+        tokens
+    );
+    for (token, range) in with_offsets {
+        assert_eq!(
+            enc.decode_single_token_bytes(token)?,
+            &text.as_bytes()[range]
+        );
+    }
+
+    let with_offsets = enc.encode_with_offsets("hello world", tt::OffsetUnit::Char);
+    assert_eq!(with_offsets, vec![(15339, 0..5), (1917, 5..11)]);
+
+    let with_offsets = enc.encode_with_offsets(text, tt::OffsetUnit::Utf16);
+    let utf16_len = text.encode_utf16().count();
+    assert_eq!(with_offsets.last().unwrap().1.end, utf16_len);
+
+    Ok(())
+}
+
+#[test]
+fn test_truncate() -> tt::Result<()> {
+    let enc = tt::get_encoding("cl100k_base")?;
+    let text = "我非常渴望与人工智能一起工作 👍 hello world";
+    for max_tokens in 0..enc.encode_ordinary(text).len() {
+        for strategy in [
+            tt::TruncationStrategy::Head,
+            tt::TruncationStrategy::Tail,
+            tt::TruncationStrategy::Middle { marker: "..." },
+        ] {
+            let (truncated, tokens) = enc.truncate(text, max_tokens, strategy);
+            assert!(tokens.len() <= max_tokens);
+            assert_eq!(enc.decode(&tokens, tt::DecodeMode::Strict)?, truncated);
+        }
+    }
+
+    let (truncated, tokens) = enc.truncate(text, 1, tt::TruncationStrategy::Tail);
+    assert_eq!(truncated, " world");
+    assert_eq!(tokens, vec![1917]);
+
+    Ok(())
+}
+
+#[test]
+fn test_from_tiktoken_file() -> tt::Result<()> {
+    use base64ct::{Base64, Encoding};
+    use std::collections::HashMap;
+
+    let mut ranks: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    ranks.extend([
+        b"he".to_vec(),
+        b"ll".to_vec(),
+        b"hell".to_vec(),
+        b"hello".to_vec(),
+    ]);
+    let contents: String = ranks
+        .iter()
+        .enumerate()
+        .map(|(rank, bytes)| format!("{} {}\n", Base64::encode_string(bytes), rank))
+        .collect();
+
+    let dir = std::env::temp_dir().join(format!("tiktoken-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("tiny_base.tiktoken");
+    std::fs::write(&path, contents)?;
+
+    let enc = tt::Encoding::from_tiktoken_file(
+        &path,
+        r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+",
+        HashMap::from([("<|endoftext|>".to_string(), 260)]),
+    )?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(enc.name(), "tiny_base");
+    assert_eq!(enc.n_vocab(), 261);
+    assert_eq!(enc.encode_ordinary("hello"), [259]);
+    assert_eq!(enc.encode_ordinary("help"), [256, 108, 112]);
+    assert_eq!(
+        enc.encode(
+            "hell<|endoftext|>",
+            tt::AllowedSpecial::All,
+            tt::DisallowedSpecial::All
+        )?,
+        [258, 260]
+    );
+    assert_eq!(enc.decode(&[259], tt::DecodeMode::Strict)?, "hello");
+
     Ok(())
 }